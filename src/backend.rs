@@ -0,0 +1,554 @@
+//! # Backend
+//! Storage access abstracted behind [StorageBackend] trait.
+//!
+//! Functions in [storage](crate::storage) use [default_backend](crate::storage::default_backend)
+//! (local files on native, HTTP API on wasm). Backends allow to choose the source explicitly:
+//! - [LocalBackend] - local filesystem (native only)
+//! - [HttpBackend] - numass server API (`api/ls`, `api/meta`, `api/process`, `files`),
+//!   on native requires `remote` feature
+//! - [MemoryBackend] - in-memory envelopes (fixtures, tests, caches)
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use numass::{protos::rsb_event, NumassMeta};
+use protobuf::Message;
+
+use crate::{
+    histogram::{HistogramParams, PointHistogram},
+    postprocess::{post_process, post_process_frames, PostProcessParams},
+    preprocess::Preprocess,
+    process::{extract_events, extract_events_iter, ProcessParams},
+    storage::{FSRepr, LoadState},
+    types::NumassEvents,
+    utils::{fill_histogram, EventCounters},
+};
+
+/// Source of numass points.
+///
+/// Only reading methods are required, [process](StorageBackend::process) is implemented on top of them
+/// (backends with server-side processing can override it).
+#[allow(async_fn_in_trait)]
+pub trait StorageBackend {
+    /// List directory (children are not loaded) or get file info (see [FSRepr::ls]).
+    async fn ls(&self, path: &Path) -> Option<FSRepr>;
+
+    /// Load point metadata only.
+    async fn load_meta(&self, path: &Path) -> Option<NumassMeta>;
+
+    /// Load and parse point binary data.
+    async fn load_point(&self, path: &Path) -> Option<rsb_event::Point>;
+
+    /// Last modification time of the file or directory.
+    async fn modified(&self, path: &Path) -> Option<SystemTime>;
+
+    /// Raw file content (e.g. processed envelope, see [load_processed](crate::storage::load_processed)).
+    async fn read(&self, path: &Path) -> Option<Vec<u8>>;
+
+    /// Process point (same as [process_point](crate::storage::process_point)).
+    async fn process(
+        &self,
+        path: &Path,
+        process: &ProcessParams,
+        postprocess: Option<&PostProcessParams>,
+    ) -> Option<(NumassMeta, Option<(NumassEvents, Preprocess)>)> {
+        let meta = self.load_meta(path).await?;
+        if let NumassMeta::Reply(numass::Reply::AcquirePoint { .. }) = &meta {
+            let point = self.load_point(path).await?;
+            let events = extract_events(Some(meta.clone()), point, process).ok()?;
+            let events = if let Some(postprocess) = postprocess {
+                post_process(events, postprocess)
+            } else {
+                events
+            };
+            Some((meta, Some(events)))
+        } else {
+            None
+        }
+    }
+
+    /// Process point directly into histogram (same as [process_point_histogram](crate::storage::process_point_histogram)).
+    /// Events are not collected, so memory consumption doesn't depend on the number of events in the point.
+    async fn process_histogram(
+        &self,
+        path: &Path,
+        process: &ProcessParams,
+        postprocess: Option<&PostProcessParams>,
+        histogram: HistogramParams,
+    ) -> Option<(
+        NumassMeta,
+        Option<(PointHistogram, EventCounters, Preprocess)>,
+    )> {
+        let meta = self.load_meta(path).await?;
+        if let NumassMeta::Reply(numass::Reply::AcquirePoint { .. }) = &meta {
+            let point = self.load_point(path).await?;
+            let (preprocess, frames) =
+                extract_events_iter(Some(meta.clone()), &point, process).ok()?;
+
+            let mut counters = EventCounters::default();
+            let histogram = if let Some(postprocess) = postprocess {
+                fill_histogram(
+                    post_process_frames(frames, &preprocess, postprocess),
+                    histogram,
+                    Some(&mut counters),
+                )
+            } else {
+                fill_histogram(frames, histogram, Some(&mut counters))
+            };
+            Some((meta, Some((histogram, counters, preprocess))))
+        } else {
+            None
+        }
+    }
+}
+
+/// Parse point from dataforge envelope bytes.
+fn parse_point(envelope: &[u8]) -> Option<rsb_event::Point> {
+    let message = dataforge::read_df_message_sync::<NumassMeta>(&mut Cursor::new(envelope)).ok()?;
+    rsb_event::Point::parse_from_bytes(&message.data.unwrap_or_default()[..]).ok()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Local filesystem backend.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalBackend;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl StorageBackend for LocalBackend {
+    /// Children removed during listing are skipped.
+    async fn ls(&self, path: &Path) -> Option<FSRepr> {
+        let meta = tokio::fs::metadata(path).await.ok()?;
+
+        if meta.is_file() {
+            return Some(FSRepr::File {
+                path: path.to_owned(),
+                modified: meta.modified().ok()?,
+            });
+        }
+
+        if meta.is_dir() {
+            let mut read_dir = tokio::fs::read_dir(path).await.ok()?;
+
+            let mut children = vec![];
+            while let Ok(Some(child)) = read_dir.next_entry().await {
+                let Ok(meta) = child.metadata().await else {
+                    continue;
+                };
+                let Ok(modified) = meta.modified() else {
+                    continue;
+                };
+                let path = child.path();
+                if meta.is_file() {
+                    children.push(FSRepr::File { path, modified });
+                } else if meta.is_dir() {
+                    children.push(FSRepr::Directory {
+                        path,
+                        children: vec![],
+                        modified,
+                        load_state: LoadState::NotLoaded,
+                    });
+                }
+            }
+            Some(FSRepr::Directory {
+                path: path.to_owned(),
+                children,
+                modified: meta.modified().ok()?,
+                load_state: LoadState::NotLoaded,
+            })
+        } else {
+            None
+        }
+    }
+
+    async fn load_meta(&self, path: &Path) -> Option<NumassMeta> {
+        let mut point_file = tokio::fs::File::open(path).await.ok()?;
+        dataforge::read_df_header_and_meta::<NumassMeta>(&mut point_file)
+            .await
+            .map_or(None, |(_, meta)| Some(meta))
+    }
+
+    async fn load_point(&self, path: &Path) -> Option<rsb_event::Point> {
+        let mut point_file = tokio::fs::File::open(path).await.ok()?;
+        let message = dataforge::read_df_message::<NumassMeta>(&mut point_file)
+            .await
+            .ok()?;
+        rsb_event::Point::parse_from_bytes(&message.data.unwrap_or_default()[..]).ok()
+    }
+
+    async fn modified(&self, path: &Path) -> Option<SystemTime> {
+        tokio::fs::metadata(path).await.ok()?.modified().ok()
+    }
+
+    async fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        tokio::fs::read(path).await.ok()
+    }
+}
+
+/// Numass server API backend.
+///
+/// Available on wasm (gloo) and on native with `remote` feature (reqwest).
+#[derive(Debug, Clone)]
+pub struct HttpBackend {
+    /// server origin without trailing slash (e.g. `http://localhost:8085`)
+    pub base_url: String,
+    #[cfg(all(not(target_arch = "wasm32"), feature = "remote"))]
+    client: reqwest::Client,
+}
+
+impl HttpBackend {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            #[cfg(all(not(target_arch = "wasm32"), feature = "remote"))]
+            client: reqwest::Client::new(),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    /// Backend for the server that serves current page (or web worker).
+    pub fn from_location() -> Self {
+        let base_url = js_sys::eval("String(new URL(self.location.href).origin)")
+            .unwrap()
+            .as_string()
+            .unwrap();
+        Self { base_url }
+    }
+
+    /// Construct API url for the file (see [api_url](crate::storage::api_url)).
+    pub fn url(&self, prefix: &str, path: &Path) -> String {
+        format!("{}/{prefix}{}", self.base_url, path.to_str().unwrap())
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) async fn get(&self, url: &str) -> Option<Vec<u8>> {
+        let response = gloo::net::http::Request::get(url).send().await.ok()?;
+        if !response.ok() {
+            return None;
+        }
+        response.binary().await.ok()
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) async fn post<T: serde::Serialize>(&self, url: &str, body: &T) -> Option<Vec<u8>> {
+        let response = gloo::net::http::Request::post(url)
+            .json(body)
+            .ok()?
+            .send()
+            .await
+            .ok()?;
+        if !response.ok() {
+            return None;
+        }
+        response.binary().await.ok()
+    }
+
+    #[cfg(all(not(target_arch = "wasm32"), feature = "remote"))]
+    pub(crate) async fn get(&self, url: &str) -> Option<Vec<u8>> {
+        let response = self.client.get(url).send().await.ok()?;
+        let response = response.error_for_status().ok()?;
+        response.bytes().await.ok().map(|bytes| bytes.to_vec())
+    }
+
+    #[cfg(all(not(target_arch = "wasm32"), feature = "remote"))]
+    pub(crate) async fn post<T: serde::Serialize>(&self, url: &str, body: &T) -> Option<Vec<u8>> {
+        let response = self.client.post(url).json(body).send().await.ok()?;
+        let response = response.error_for_status().ok()?;
+        response.bytes().await.ok().map(|bytes| bytes.to_vec())
+    }
+}
+
+#[cfg(any(target_arch = "wasm32", feature = "remote"))]
+impl StorageBackend for HttpBackend {
+    async fn ls(&self, path: &Path) -> Option<FSRepr> {
+        let payload = self.get(&self.url("api/ls", path)).await?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    async fn load_meta(&self, path: &Path) -> Option<NumassMeta> {
+        let payload = self.get(&self.url("api/meta", path)).await?;
+        serde_json::from_slice::<Option<NumassMeta>>(&payload).ok()?
+    }
+
+    async fn load_point(&self, path: &Path) -> Option<rsb_event::Point> {
+        parse_point(&self.get(&self.url("files", path)).await?)
+    }
+
+    async fn modified(&self, path: &Path) -> Option<SystemTime> {
+        match self.ls(path).await? {
+            FSRepr::File { modified, .. } | FSRepr::Directory { modified, .. } => Some(modified),
+        }
+    }
+
+    async fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        self.get(&self.url("files", path)).await
+    }
+
+    /// Points are processed on the server, only events are transferred.
+    async fn process(
+        &self,
+        path: &Path,
+        process: &ProcessParams,
+        postprocess: Option<&PostProcessParams>,
+    ) -> Option<(NumassMeta, Option<(NumassEvents, Preprocess)>)> {
+        let meta = self.load_meta(path).await?;
+        if let NumassMeta::Reply(numass::Reply::AcquirePoint { .. }) = &meta {
+            let processed = self
+                .post(&self.url("api/process", path), &(process, postprocess))
+                .await?;
+            Some((
+                meta,
+                rmp_serde::from_slice::<Option<(NumassEvents, Preprocess)>>(&processed).ok()?,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Events are processed on the server and histogram is filled locally.
+    async fn process_histogram(
+        &self,
+        path: &Path,
+        process: &ProcessParams,
+        postprocess: Option<&PostProcessParams>,
+        histogram: HistogramParams,
+    ) -> Option<(
+        NumassMeta,
+        Option<(PointHistogram, EventCounters, Preprocess)>,
+    )> {
+        let (meta, processed) = self.process(path, process, postprocess).await?;
+        Some((
+            meta,
+            processed.map(|(events, preprocess)| {
+                let mut counters = EventCounters::default();
+                let histogram = fill_histogram(events.into_iter(), histogram, Some(&mut counters));
+                (histogram, counters, preprocess)
+            }),
+        ))
+    }
+}
+
+/// File stored in [MemoryBackend].
+#[derive(Debug, Clone)]
+pub struct MemoryFile {
+    /// raw dataforge envelope (same bytes as in the point file)
+    pub envelope: Vec<u8>,
+    pub modified: SystemTime,
+}
+
+/// In-memory backend.
+/// Directories are not stored explicitly, they are derived from file paths.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    pub files: BTreeMap<PathBuf, MemoryFile>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add (or replace) file with envelope bytes.
+    pub fn insert(&mut self, path: PathBuf, envelope: Vec<u8>, modified: SystemTime) {
+        self.files.insert(path, MemoryFile { envelope, modified });
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    async fn ls(&self, path: &Path) -> Option<FSRepr> {
+        if let Some(file) = self.files.get(path) {
+            return Some(FSRepr::File {
+                path: path.to_owned(),
+                modified: file.modified,
+            });
+        }
+
+        let mut children = BTreeMap::<PathBuf, FSRepr>::new();
+        for (file_path, file) in self.files.range(path.to_owned()..) {
+            let Ok(relative) = file_path.strip_prefix(path) else {
+                break;
+            };
+            let Some(name) = relative.components().next() else {
+                continue;
+            };
+            let child_path = path.join(name);
+            if &child_path == file_path {
+                children.insert(
+                    child_path.to_owned(),
+                    FSRepr::File {
+                        path: child_path,
+                        modified: file.modified,
+                    },
+                );
+            } else {
+                let child = children
+                    .entry(child_path.to_owned())
+                    .or_insert_with(|| FSRepr::Directory {
+                        path: child_path,
+                        children: vec![],
+                        modified: SystemTime::UNIX_EPOCH,
+                        load_state: LoadState::NotLoaded,
+                    });
+                if let FSRepr::Directory { modified, .. } = child {
+                    *modified = (*modified).max(file.modified);
+                }
+            }
+        }
+
+        if children.is_empty() {
+            return None;
+        }
+
+        let mut children = children.into_values().collect::<Vec<_>>();
+        children.sort_by(|v1, v2| {
+            natord::compare(
+                v1.to_filename().as_os_str().to_str().unwrap(),
+                v2.to_filename().as_os_str().to_str().unwrap(),
+            )
+        });
+        let modified = self.modified(path).await?;
+
+        Some(FSRepr::Directory {
+            path: path.to_owned(),
+            children,
+            modified,
+            load_state: LoadState::NotLoaded,
+        })
+    }
+
+    async fn load_meta(&self, path: &Path) -> Option<NumassMeta> {
+        let file = self.files.get(path)?;
+        dataforge::read_df_header_and_meta_sync::<NumassMeta>(&mut Cursor::new(&file.envelope))
+            .map_or(None, |(_, meta)| Some(meta))
+    }
+
+    async fn load_point(&self, path: &Path) -> Option<rsb_event::Point> {
+        parse_point(&self.files.get(path)?.envelope)
+    }
+
+    /// Directory modified time is the latest modified time of its files.
+    async fn modified(&self, path: &Path) -> Option<SystemTime> {
+        self.files
+            .range(path.to_owned()..)
+            .take_while(|(file_path, _)| file_path.starts_with(path))
+            .map(|(_, file)| file.modified)
+            .max()
+    }
+
+    async fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        Some(self.files.get(path)?.envelope.clone())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn backend() -> MemoryBackend {
+        let mut backend = MemoryBackend::new();
+        for (path, secs) in [
+            ("root/p0", 1),
+            ("root/set_1/p1", 2),
+            ("root/set_1/p2", 5),
+            ("root/set_10/p1", 3),
+            ("root/set_2/p1", 4),
+        ] {
+            backend.insert(
+                PathBuf::from(path),
+                path.as_bytes().to_vec(),
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            );
+        }
+        backend
+    }
+
+    fn names(node: &FSRepr) -> Vec<(PathBuf, bool)> {
+        match node {
+            FSRepr::Directory { children, .. } => children
+                .iter()
+                .map(|child| (child.to_filename(), matches!(child, FSRepr::File { .. })))
+                .collect(),
+            FSRepr::File { .. } => panic!("directory expected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_ls() {
+        let backend = backend();
+
+        let root = backend.ls(Path::new("root")).await.unwrap();
+        assert_eq!(
+            names(&root),
+            vec![
+                (PathBuf::from("root/p0"), true),
+                (PathBuf::from("root/set_1"), false),
+                (PathBuf::from("root/set_2"), false),
+                (PathBuf::from("root/set_10"), false),
+            ]
+        );
+        let modified = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(backend.modified(Path::new("root")).await, Some(modified(5)));
+
+        let set = backend.ls(Path::new("root/set_1")).await.unwrap();
+        assert_eq!(
+            names(&set),
+            vec![
+                (PathBuf::from("root/set_1/p1"), true),
+                (PathBuf::from("root/set_1/p2"), true),
+            ]
+        );
+
+        match backend.ls(Path::new("root/set_10/p1")).await {
+            Some(FSRepr::File { path, modified: time }) => {
+                assert_eq!(path, PathBuf::from("root/set_10/p1"));
+                assert_eq!(time, modified(3));
+            }
+            node => panic!("file expected, got {node:?}"),
+        }
+
+        assert!(backend.ls(Path::new("root/set_3")).await.is_none());
+        assert!(backend.ls(Path::new("root/set")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn memory_load_meta() {
+        let backend = backend();
+        assert!(backend.load_meta(Path::new("root/missing")).await.is_none());
+        // not a dataforge envelope
+        assert!(backend.load_meta(Path::new("root/p0")).await.is_none());
+        assert_eq!(
+            backend.read(Path::new("root/p0")).await,
+            Some(b"root/p0".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_process() {
+        let backend = backend();
+        let process = ProcessParams::default();
+        assert!(backend
+            .process(Path::new("root/missing"), &process, None)
+            .await
+            .is_none());
+        assert!(backend
+            .process(Path::new("root/set_1/p1"), &process, None)
+            .await
+            .is_none());
+        assert!(backend
+            .process_histogram(
+                Path::new("root/set_1/p1"),
+                &process,
+                None,
+                HistogramParams {
+                    range: 0.0..100.0,
+                    bins: 10,
+                },
+            )
+            .await
+            .is_none());
+    }
+}
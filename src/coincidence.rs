@@ -0,0 +1,263 @@
+//! # Coincidence
+//! Multiplicity and coincidence analysis of processed events (crosstalk and charge sharing studies).
+//!
+//! Events of the frame are grouped into coincidences: sorted by offset, an event joins the group
+//! if it is closer than `window` (in ns) to the previous event of the group.
+//! Pixel adjacency is taken from detector geometry (centre pixel borders all other pixels).
+//! ```no_run
+//! # use processing::{coincidence::CoincidenceStats, types::NumassEvents};
+//! # let events = NumassEvents::new();
+//! let stats = CoincidenceStats::from_events(&events, 100);
+//! println!("{:?}", stats.multiplicity);
+//! println!("ch1-ch2 coincidences: {}", stats.pairs[0][1]);
+//! println!("adjacent fraction: {}", stats.adjacent_fraction());
+//! println!("ch1-ch2 correlation: {:?}", stats.correlation(0, 1));
+//! ```
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    postprocess::is_neighbour,
+    types::{EventOffset, EventPart, FrameEvent, NumassEvent, NumassEvents},
+};
+
+/// Group events of the frame into coincidences (only [FrameEvent::Event]s are used).
+pub fn coincidence_groups(events: &[NumassEvent], window: EventOffset) -> Vec<Vec<EventPart>> {
+    let mut parts = events
+        .iter()
+        .filter_map(|(offset, event)| {
+            if let FrameEvent::Event {
+                channel, amplitude, ..
+            } = event
+            {
+                Some(EventPart {
+                    offset: *offset,
+                    channel: *channel,
+                    amplitude: *amplitude,
+                })
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    parts.sort_by_key(|part| part.offset);
+
+    let mut groups: Vec<Vec<EventPart>> = vec![];
+    for part in parts {
+        match groups.last_mut() {
+            Some(group) if part.offset - group.last().unwrap().offset < window => group.push(part),
+            _ => groups.push(vec![part]),
+        }
+    }
+    groups
+}
+
+/// Accumulated coincidence statistics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoincidenceStats {
+    /// coincidence window in ns
+    pub window: EventOffset,
+    /// number of coincidence groups by multiplicity (1 - single event)
+    pub multiplicity: BTreeMap<usize, usize>,
+    /// coincidence counts for every pair of events in a group by channels (symmetric matrix,
+    /// diagonal counts coincidences in the same channel)
+    pub pairs: [[usize; 7]; 7],
+    /// amplitudes of coincident events by channel pair `[ch_1, ch_2]` (`ch_1 <= ch_2`),
+    /// each entry is `[amplitude_1, amplitude_2]`
+    pub amplitudes: BTreeMap<[u8; 2], Vec<[f32; 2]>>,
+}
+
+impl CoincidenceStats {
+    pub fn new(window: EventOffset) -> Self {
+        Self {
+            window,
+            multiplicity: BTreeMap::new(),
+            pairs: [[0; 7]; 7],
+            amplitudes: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_events(events: &NumassEvents, window: EventOffset) -> Self {
+        let mut stats = Self::new(window);
+        events.values().for_each(|frame| stats.add_frame(frame));
+        stats
+    }
+
+    /// Add events of a single frame.
+    pub fn add_frame(&mut self, events: &[NumassEvent]) {
+        for group in coincidence_groups(events, self.window) {
+            *self.multiplicity.entry(group.len()).or_default() += 1;
+
+            for (idx, first) in group.iter().enumerate() {
+                for second in &group[idx + 1..] {
+                    let (first, second) = if first.channel <= second.channel {
+                        (first, second)
+                    } else {
+                        (second, first)
+                    };
+                    let (ch_1, ch_2) = (first.channel as usize, second.channel as usize);
+                    self.pairs[ch_1][ch_2] += 1;
+                    if ch_1 != ch_2 {
+                        self.pairs[ch_2][ch_1] += 1;
+                    }
+                    self.amplitudes
+                        .entry([first.channel, second.channel])
+                        .or_default()
+                        .push([first.amplitude, second.amplitude]);
+                }
+            }
+        }
+    }
+
+    /// Number of coincidences between different adjacent pixels.
+    pub fn adjacent(&self) -> usize {
+        self.count_pairs(|ch_1, ch_2| ch_1 != ch_2 && is_neighbour(ch_1, ch_2))
+    }
+
+    /// Number of coincidences between different not adjacent pixels.
+    pub fn non_adjacent(&self) -> usize {
+        self.count_pairs(|ch_1, ch_2| !is_neighbour(ch_1, ch_2))
+    }
+
+    /// Fraction of adjacent pixels coincidences among coincidences between different pixels.
+    /// Charge sharing gives values close to 1, random coincidences - close to geometric fraction.
+    pub fn adjacent_fraction(&self) -> f32 {
+        let adjacent = self.adjacent();
+        let total = adjacent + self.non_adjacent();
+        if total == 0 {
+            0.0
+        } else {
+            adjacent as f32 / total as f32
+        }
+    }
+
+    /// Pearson correlation of coincident events amplitudes for the channel pair.
+    /// Returns `None` if there are less than 2 coincidences or amplitudes are constant.
+    pub fn correlation(&self, ch_1: u8, ch_2: u8) -> Option<f32> {
+        let amplitudes = self.amplitudes.get(&[ch_1.min(ch_2), ch_1.max(ch_2)])?;
+        if amplitudes.len() < 2 {
+            return None;
+        }
+
+        let n = amplitudes.len() as f64;
+        let mean = |idx: usize| amplitudes.iter().map(|a| a[idx] as f64).sum::<f64>() / n;
+        let (mean_1, mean_2) = (mean(0), mean(1));
+
+        let (mut cov, mut var_1, mut var_2) = (0.0, 0.0, 0.0);
+        for [a_1, a_2] in amplitudes {
+            let (d_1, d_2) = (*a_1 as f64 - mean_1, *a_2 as f64 - mean_2);
+            cov += d_1 * d_2;
+            var_1 += d_1 * d_1;
+            var_2 += d_2 * d_2;
+        }
+
+        if var_1 == 0.0 || var_2 == 0.0 {
+            None
+        } else {
+            Some((cov / (var_1 * var_2).sqrt()) as f32)
+        }
+    }
+
+    fn count_pairs(&self, filter: impl Fn(u8, u8) -> bool) -> usize {
+        (0..7u8)
+            .flat_map(|ch_1| (ch_1..7u8).map(move |ch_2| (ch_1, ch_2)))
+            .filter(|(ch_1, ch_2)| filter(*ch_1, *ch_2))
+            .map(|(ch_1, ch_2)| self.pairs[ch_1 as usize][ch_2 as usize])
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(offset: EventOffset, channel: u8, amplitude: f32) -> NumassEvent {
+        (
+            offset,
+            FrameEvent::Event {
+                channel,
+                amplitude,
+                size: 10,
+            },
+        )
+    }
+
+    /// Chain 0-90-180 is longer than window, but every step is inside it.
+    fn chained_frame() -> Vec<NumassEvent> {
+        vec![
+            event(0, 3, 1.0),
+            (100, FrameEvent::Reset { size: 10 }),
+            event(10_000, 4, 1.0),
+            event(180, 3, 1.0),
+            event(90, 6, 1.0),
+        ]
+    }
+
+    fn events() -> NumassEvents {
+        NumassEvents::from([
+            // adjacent pair
+            (0, vec![event(0, 0, 10.0), event(50, 1, 20.0)]),
+            // non adjacent pairs with linearly dependent amplitudes
+            (100_000, vec![event(0, 0, 5.0), event(30, 2, 8.0)]),
+            (200_000, vec![event(0, 0, 10.0), event(30, 2, 16.0)]),
+            (300_000, vec![event(0, 2, 4.0), event(30, 0, 2.5)]),
+            (400_000, chained_frame()),
+            // non adjacent pairs with constant amplitude of channel 1
+            (500_000, vec![event(0, 1, 7.0), event(20, 4, 1.0)]),
+            (600_000, vec![event(0, 1, 7.0), event(20, 4, 3.0)]),
+        ])
+    }
+
+    #[test]
+    fn chained_group() {
+        let groups = coincidence_groups(&chained_frame(), 100)
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|part| (part.offset, part.channel))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            vec![vec![(0, 3), (90, 6), (180, 3)], vec![(10_000, 4)]]
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let stats = CoincidenceStats::from_events(&events(), 100);
+        assert_eq!(stats.multiplicity, BTreeMap::from([(1, 1), (2, 6), (3, 1)]));
+
+        assert_eq!(stats.pairs[0][1], 1);
+        assert_eq!(stats.pairs[0][2], 3);
+        assert_eq!(stats.pairs[2][0], 3);
+        assert_eq!(stats.pairs[3][6], 2);
+        assert_eq!(stats.pairs[3][3], 1);
+        assert_eq!(stats.pairs[1][4], 2);
+        assert_eq!(stats.pairs.iter().flatten().sum::<usize>(), 2 * 8 + 1);
+
+        assert_eq!(stats.adjacent(), 3);
+        assert_eq!(stats.non_adjacent(), 5);
+        assert_eq!(stats.adjacent_fraction(), 3.0 / 8.0);
+        assert_eq!(CoincidenceStats::new(100).adjacent_fraction(), 0.0);
+    }
+
+    #[test]
+    fn correlation() {
+        let stats = CoincidenceStats::from_events(&events(), 100);
+        assert_eq!(
+            stats.amplitudes[&[0, 2]],
+            vec![[5.0, 8.0], [10.0, 16.0], [2.5, 4.0]]
+        );
+        assert!((stats.correlation(0, 2).unwrap() - 1.0).abs() < 1e-6);
+        assert_eq!(stats.correlation(2, 0), stats.correlation(0, 2));
+        // zero variance of channel 1
+        assert_eq!(stats.correlation(1, 4), None);
+        // single coincidence
+        assert_eq!(stats.correlation(0, 1), None);
+        assert_eq!(stats.correlation(5, 6), None);
+    }
+}
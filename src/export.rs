@@ -0,0 +1,323 @@
+//! # Export
+//! Export of processed events into columnar formats for analysis outside Rust.
+//!
+//! [NumassEvents] are flattened into a table with one row per event:
+//! `frame_time`, `offset`, `kind`, `channel`, `amplitude`, `size`.
+//! Each column is written into a separate NumPy `.npy` file, so the point can be loaded with
+//! ```python
+//! columns = {name: np.load(f"{dir}/{name}.npy") for name in ["frame_time", "offset", "kind", "channel", "amplitude", "size"]}
+//! ```
+//! Columns are streamed to disk frame by frame, so export does not need a second copy of the events in memory.
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::Path,
+};
+
+use crate::{
+    preprocess::Preprocess,
+    types::{EventOffset, NumassEvent, NumassEvents},
+};
+
+pub use crate::types::{KIND_EVENT, KIND_FRAME, KIND_OVERFLOW, KIND_RESET, NO_CHANNEL};
+
+#[cfg(feature = "root")]
+/// Name of the events tree in ROOT export (see [export_root]).
+pub const EVENTS_TREE: &str = "events";
+
+/// Names of the exported columns (file names without `.npy` extension).
+pub const COLUMNS: [&str; 6] = ["frame_time", "offset", "kind", "channel", "amplitude", "size"];
+
+/// Size of the reserved `.npy` header (magic + version + header length + dict).
+/// Must be divisible by 64 (see NumPy format specification).
+const NPY_HEADER_SIZE: usize = 128;
+
+/// Scalar types that can be stored in `.npy` column.
+pub trait NpyScalar: Copy {
+    /// NumPy dtype descriptor.
+    const DESCR: &'static str;
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()>;
+}
+
+macro_rules! impl_npy_scalar {
+    ($($ty:ty => $descr:literal),*) => {
+        $(impl NpyScalar for $ty {
+            const DESCR: &'static str = $descr;
+            fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+        })*
+    };
+}
+
+impl_npy_scalar!(u8 => "|u1", u16 => "<u2", u32 => "<u4", u64 => "<u8", f32 => "<f4");
+
+/// One-dimensional `.npy` file written incrementally.
+///
+/// Header is reserved on creation and rewritten with the real length on [finish](NpyColumn::finish).
+pub struct NpyColumn<T: NpyScalar> {
+    writer: BufWriter<File>,
+    len: u64,
+    _type: PhantomData<T>,
+}
+
+impl<T: NpyScalar> NpyColumn<T> {
+    pub fn create(filepath: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(filepath)?);
+        writer.write_all(&npy_header(T::DESCR, 0))?;
+        Ok(Self {
+            writer,
+            len: 0,
+            _type: PhantomData,
+        })
+    }
+
+    pub fn push(&mut self, value: T) -> io::Result<()> {
+        self.len += 1;
+        value.write_le(&mut self.writer)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&npy_header(T::DESCR, self.len))?;
+        self.writer.flush()
+    }
+}
+
+fn npy_header(descr: &str, len: u64) -> Vec<u8> {
+    let dict = format!("{{'descr': '{descr}', 'fortran_order': False, 'shape': ({len},), }}");
+    let dict_len = NPY_HEADER_SIZE - 10;
+
+    let mut header = Vec::with_capacity(NPY_HEADER_SIZE);
+    header.extend_from_slice(b"\x93NUMPY\x01\x00");
+    header.extend_from_slice(&(dict_len as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header.resize(NPY_HEADER_SIZE - 1, b' ');
+    header.push(b'\n');
+    header
+}
+
+/// Streaming writer of flattened events into a directory of `.npy` columns (see [module docs](crate::export)).
+pub struct NpyEventsWriter {
+    frame_time: NpyColumn<u64>,
+    offset: NpyColumn<EventOffset>,
+    kind: NpyColumn<u8>,
+    channel: NpyColumn<u8>,
+    amplitude: NpyColumn<f32>,
+    size: NpyColumn<u16>,
+}
+
+impl NpyEventsWriter {
+    /// Create column files in `dir` (directory will be created if not exists).
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let column = |name: &str| dir.join(format!("{name}.npy"));
+        Ok(Self {
+            frame_time: NpyColumn::create(&column(COLUMNS[0]))?,
+            offset: NpyColumn::create(&column(COLUMNS[1]))?,
+            kind: NpyColumn::create(&column(COLUMNS[2]))?,
+            channel: NpyColumn::create(&column(COLUMNS[3]))?,
+            amplitude: NpyColumn::create(&column(COLUMNS[4]))?,
+            size: NpyColumn::create(&column(COLUMNS[5]))?,
+        })
+    }
+
+    /// Append events of a single frame.
+    pub fn write_frame(&mut self, frame_time: u64, events: &[NumassEvent]) -> io::Result<()> {
+        for (offset, event) in events {
+            let (kind, channel, amplitude, size) = event.to_columns();
+
+            self.frame_time.push(frame_time)?;
+            self.offset.push(*offset)?;
+            self.kind.push(kind)?;
+            self.channel.push(channel)?;
+            self.amplitude.push(amplitude)?;
+            self.size.push(size)?;
+        }
+        Ok(())
+    }
+
+    /// Write final headers and flush all columns.
+    pub fn finish(self) -> io::Result<()> {
+        self.frame_time.finish()?;
+        self.offset.finish()?;
+        self.kind.finish()?;
+        self.channel.finish()?;
+        self.amplitude.finish()?;
+        self.size.finish()
+    }
+}
+
+/// Export processed point into `dir` as `.npy` columns + `preprocess.json`.
+///
+/// Events are consumed frame by frame, so memory is released while columns are written.
+pub fn export_npy(dir: &Path, events: NumassEvents, preprocess: &Preprocess) -> io::Result<()> {
+    let mut writer = NpyEventsWriter::create(dir)?;
+    for (frame_time, events) in events {
+        writer.write_frame(frame_time, &events)?;
+    }
+    writer.finish()?;
+
+    let preprocess_file = BufWriter::new(File::create(dir.join("preprocess.json"))?);
+    serde_json::to_writer_pretty(preprocess_file, preprocess).map_err(io::Error::from)
+}
+
+#[cfg(feature = "root")]
+/// Export processed point into ROOT file (requires `root` feature).
+///
+/// File will contain three trees:
+/// - `events` - flattened events (same columns as in [NpyEventsWriter])
+/// - `meta` - single entry with point metadata (`hv`, `start_time`, `acquisition_time`, `effective_time`, `frame_len`, `cutoff_bin_size`)
+/// - `bad_blocks` - indices of cut blocks (see [Preprocess::bad_blocks])
+pub fn export_root(
+    filepath: &Path,
+    events: &NumassEvents,
+    preprocess: &Preprocess,
+) -> Result<(), String> {
+    use oxyroot::{RootFile, WriterTree};
+
+    let mut file = RootFile::create(filepath).map_err(|err| format!("{err:?}"))?;
+
+    let rows = || {
+        events.iter().flat_map(|(frame_time, events)| {
+            events.iter().map(move |(offset, event)| {
+                let (kind, channel, amplitude, size) = event.to_columns();
+                (*frame_time, *offset, kind, channel, amplitude, size)
+            })
+        })
+    };
+
+    let mut tree = WriterTree::new(EVENTS_TREE);
+    tree.new_branch(COLUMNS[0], rows().map(|row| row.0));
+    tree.new_branch(COLUMNS[1], rows().map(|row| row.1));
+    tree.new_branch(COLUMNS[2], rows().map(|row| row.2));
+    tree.new_branch(COLUMNS[3], rows().map(|row| row.3));
+    tree.new_branch(COLUMNS[4], rows().map(|row| row.4));
+    tree.new_branch(COLUMNS[5], rows().map(|row| row.5));
+    tree.write(&mut file).map_err(|err| format!("{err:?}"))?;
+
+    let mut meta = WriterTree::new("meta");
+    meta.new_branch("hv", std::iter::once(preprocess.hv));
+    meta.new_branch(
+        "start_time",
+        std::iter::once(preprocess.start_time.and_utc().timestamp()),
+    );
+    meta.new_branch("acquisition_time", std::iter::once(preprocess.acquisition_time));
+    meta.new_branch("effective_time", std::iter::once(preprocess.effective_time()));
+    meta.new_branch("frame_len", std::iter::once(preprocess.frame_len));
+    meta.new_branch(
+        "cutoff_bin_size",
+        std::iter::once(preprocess.cutoff_bin_size),
+    );
+    meta.write(&mut file).map_err(|err| format!("{err:?}"))?;
+
+    let mut bad_blocks = WriterTree::new("bad_blocks");
+    bad_blocks.new_branch(
+        "block",
+        preprocess.bad_blocks.iter().map(|block| *block as u64),
+    );
+    bad_blocks.write(&mut file).map_err(|err| format!("{err:?}"))?;
+
+    file.close().map_err(|err| format!("{err:?}"))
+}
+
+#[cfg(feature = "root")]
+/// Process point with [ToROOTOptions](crate::viewer::ToROOTOptions) and export it with [export_root].
+/// `output` is a path to the resulting ROOT file.
+pub async fn process_to_root(options: &crate::viewer::ToROOTOptions, output: &Path) -> Result<(), String> {
+    let Some((_, Some((events, preprocess)))) = crate::storage::process_point(
+        &options.filepath,
+        &options.process,
+        Some(&options.postprocess),
+    )
+    .await
+    else {
+        return Err(format!("{:?} is not a numass point", options.filepath));
+    };
+
+    export_root(output, &events, &preprocess)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use chrono::NaiveDateTime;
+
+    use super::*;
+    use crate::types::FrameEvent;
+
+    #[test]
+    fn npy_header_and_shape() {
+        let dir = std::env::temp_dir().join(format!("processing-export-{}", std::process::id()));
+        let events = NumassEvents::from([
+            (
+                100,
+                vec![
+                    (
+                        10,
+                        FrameEvent::Event {
+                            channel: 5,
+                            amplitude: 12.5,
+                            size: 8,
+                        },
+                    ),
+                    (20, FrameEvent::Reset { size: 110 }),
+                ],
+            ),
+            (
+                200,
+                vec![(
+                    0,
+                    FrameEvent::Overflow {
+                        channel: 1,
+                        size: 4,
+                    },
+                )],
+            ),
+        ]);
+        let preprocess = Preprocess {
+            baseline: None,
+            hv: 14000.0,
+            start_time: NaiveDateTime::default(),
+            acquisition_time: 1000,
+            frame_len: 100,
+            bad_blocks: BTreeSet::new(),
+            cutoff_bin_size: 1000,
+            bad_block_records: BTreeMap::new(),
+            quality: Default::default(),
+            block_rates: vec![],
+        };
+        export_npy(&dir, events, &preprocess).unwrap();
+
+        let item_sizes = [8, 4, 1, 1, 4, 2];
+        let descrs = ["<u8", "<u4", "|u1", "|u1", "<f4", "<u2"];
+        for ((name, item_size), descr) in COLUMNS.iter().zip(item_sizes).zip(descrs) {
+            let data = std::fs::read(dir.join(format!("{name}.npy"))).unwrap();
+            assert_eq!(data.len(), NPY_HEADER_SIZE + 3 * item_size, "{name}");
+
+            let header = &data[..NPY_HEADER_SIZE];
+            assert!(header.starts_with(b"\x93NUMPY\x01\x00"), "{name}");
+            let dict_len = u16::from_le_bytes([header[8], header[9]]) as usize;
+            assert_eq!(10 + dict_len, NPY_HEADER_SIZE, "{name}");
+            assert_eq!(header[NPY_HEADER_SIZE - 1], b'\n', "{name}");
+
+            let dict = std::str::from_utf8(&header[10..]).unwrap().trim_end();
+            assert_eq!(
+                dict,
+                format!("{{'descr': '{descr}', 'fortran_order': False, 'shape': (3,), }}")
+            );
+        }
+
+        let frame_time = std::fs::read(dir.join("frame_time.npy")).unwrap();
+        let frame_time = frame_time[NPY_HEADER_SIZE..]
+            .chunks_exact(8)
+            .map(|value| u64::from_le_bytes(value.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(frame_time, [100, 100, 200]);
+        assert!(dir.join("preprocess.json").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
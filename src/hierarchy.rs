@@ -0,0 +1,267 @@
+//! # Hierarchy
+//! Typed handles for numass storage layout (RUN/FILL/SET/POINT).
+//!
+//! Storage layout example:
+//! ```text
+//! 2024_03/                      <- run
+//!     Tritium_1/                <- fill (optional level)
+//!         set_1/                <- set
+//!             p0(30s)(HV1=14000) <- point
+//!             p1(30s)(HV1=14500)
+//! ```
+//! All listing goes through [FSRepr::ls], so handles work both natively and via the wasm API.
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+};
+
+use numass::{ExternalMeta, NumassMeta, Reply};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{load_meta, FSRepr};
+
+/// Max depth of directories between run and set (fill directories).
+const MAX_SET_DEPTH: usize = 3;
+
+/// Numass run (top-level directory of the session, e.g. `2024_03`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Run {
+    pub path: PathBuf,
+}
+
+/// Numass set (directory with points).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Set {
+    pub path: PathBuf,
+}
+
+/// Numass point (single acquisition file).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    pub path: PathBuf,
+    /// point index in the set (acquisition order)
+    pub index: usize,
+    /// HV1 value (from name or [meta](Point::update_from_meta))
+    pub hv: Option<f32>,
+    /// acquisition time in nanoseconds (from name or [meta](Point::update_from_meta))
+    pub acquisition_time: Option<u64>,
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+fn natural_cmp(first: &Path, second: &Path) -> Ordering {
+    natord::compare(
+        first.to_str().unwrap_or_default(),
+        second.to_str().unwrap_or_default(),
+    )
+}
+
+impl Run {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn name(&self) -> &str {
+        file_name(&self.path)
+    }
+
+    /// Find all sets of the run (directories containing points) in natural order.
+    pub async fn sets(&self) -> Vec<Set> {
+        let mut sets = vec![];
+        let mut current = vec![self.path.to_owned()];
+
+        for _ in 0..MAX_SET_DEPTH {
+            let mut next = vec![];
+            for dir in current {
+                if let Some(FSRepr::Directory { children, .. }) = FSRepr::ls(dir.to_owned()).await {
+                    let has_points = children.iter().any(|child| match child {
+                        FSRepr::File { path, .. } => Point::from_path(path.to_owned()).is_some(),
+                        FSRepr::Directory { .. } => false,
+                    });
+                    if has_points {
+                        sets.push(Set { path: dir });
+                    } else {
+                        next.extend(children.into_iter().filter_map(|child| match child {
+                            FSRepr::Directory { path, .. } => Some(path),
+                            FSRepr::File { .. } => None,
+                        }));
+                    }
+                }
+            }
+            current = next;
+        }
+
+        sets.sort_by(|first, second| natural_cmp(&first.path, &second.path));
+        sets
+    }
+}
+
+impl Set {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn name(&self) -> &str {
+        file_name(&self.path)
+    }
+
+    /// Run that contains this set directly (RUN/SET layout).
+    pub fn run(&self) -> Option<Run> {
+        Some(Run::new(self.path.parent()?.to_owned()))
+    }
+
+    /// Run that contains this set through a fill directory (RUN/FILL/SET layout).
+    pub fn fill_run(&self) -> Option<Run> {
+        Some(Run::new(self.path.parent()?.parent()?.to_owned()))
+    }
+
+    /// Points of the set in acquisition order (files that are not points are skipped).
+    pub async fn points(&self) -> Vec<Point> {
+        let mut points = if let Some(FSRepr::Directory { children, .. }) =
+            FSRepr::ls(self.path.to_owned()).await
+        {
+            children
+                .into_iter()
+                .filter_map(|child| match child {
+                    FSRepr::File { path, .. } => Point::from_path(path),
+                    FSRepr::Directory { .. } => None,
+                })
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        points.sort_by(Point::cmp_acquisition);
+        points
+    }
+}
+
+impl Point {
+    /// Parse point handle from the file path.
+    /// Returns `None` if filename doesn't look like numass point (`p{index}...`).
+    pub fn from_path(path: PathBuf) -> Option<Self> {
+        let name = file_name(&path);
+
+        let digits = name
+            .strip_prefix('p')?
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect::<String>();
+        let index = digits.parse::<usize>().ok()?;
+
+        let hv = name
+            .split_once("HV1=")
+            .and_then(|(_, rest)| rest.split(')').next())
+            .and_then(|hv| hv.parse::<f32>().ok());
+
+        let acquisition_time = name
+            .split('(')
+            .filter_map(|part| part.strip_suffix("s)").or_else(|| part.strip_suffix('s')))
+            .find_map(|time| time.parse::<f64>().ok())
+            .map(|time| (time * 1e9) as u64);
+
+        Some(Self {
+            path,
+            index,
+            hv,
+            acquisition_time,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        file_name(&self.path)
+    }
+
+    /// Acquisition order of points (by index, then by natural order of paths).
+    pub fn cmp_acquisition(&self, other: &Self) -> Ordering {
+        self.index
+            .cmp(&other.index)
+            .then_with(|| natural_cmp(&self.path, &other.path))
+    }
+
+    /// Set that contains this point.
+    pub fn set(&self) -> Option<Set> {
+        Some(Set::new(self.path.parent()?.to_owned()))
+    }
+
+    /// Load point metadata from the storage.
+    pub async fn meta(&self) -> Option<NumassMeta> {
+        load_meta(&self.path).await
+    }
+
+    /// Override HV and acquisition time with values from metadata (if present).
+    pub fn update_from_meta(&mut self, meta: &NumassMeta) {
+        if let NumassMeta::Reply(Reply::AcquirePoint {
+            acquisition_time,
+            external_meta,
+            ..
+        }) = meta
+        {
+            self.acquisition_time = Some((*acquisition_time * 1e9) as u64);
+            if let Some(ExternalMeta {
+                hv1_value: Some(hv),
+                ..
+            }) = external_meta
+            {
+                self.hv = Some(*hv);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn point_from_path() {
+        let point =
+            Point::from_path("/data/2024_03/Tritium_1/set_1/p12(30s)(HV1=14000)".into()).unwrap();
+        assert_eq!(point.index, 12);
+        assert_eq!(point.hv, Some(14000.0));
+        assert_eq!(point.acquisition_time, Some(30_000_000_000));
+        assert_eq!(point.name(), "p12(30s)(HV1=14000)");
+
+        let set = point.set().unwrap();
+        assert_eq!(set.name(), "set_1");
+        assert_eq!(set.run().unwrap().name(), "Tritium_1");
+        assert_eq!(set.fill_run().unwrap().name(), "2024_03");
+
+        let point = Point::from_path("/data/2024_03/set_1/p3(10s)".into()).unwrap();
+        assert_eq!(point.index, 3);
+        assert_eq!(point.hv, None);
+        assert_eq!(point.acquisition_time, Some(10_000_000_000));
+
+        for path in [
+            "/data/2024_03/set_1/meta",
+            "/data/2024_03/set_1/p",
+            "/data/pa(30s)",
+        ] {
+            assert!(Point::from_path(path.into()).is_none(), "{path}");
+        }
+    }
+
+    #[test]
+    fn point_acquisition_order() {
+        let mut points = [
+            "p10(30s)(HV1=16000)",
+            "p9(30s)(HV1=15500)",
+            "p0(30s)(HV1=14000)",
+        ]
+        .into_iter()
+        .map(|name| Point::from_path(PathBuf::from("/data/set_1").join(name)).unwrap())
+        .collect::<Vec<_>>();
+        points.sort_by(Point::cmp_acquisition);
+        assert_eq!(
+            points.iter().map(Point::name).collect::<Vec<_>>(),
+            [
+                "p0(30s)(HV1=14000)",
+                "p9(30s)(HV1=15500)",
+                "p10(30s)(HV1=16000)"
+            ]
+        );
+    }
+}
@@ -21,6 +21,24 @@ pub struct HistogramParams {
     pub bins: usize,
 }
 
+/// Current version of [PointHistogramJson] schema.
+pub const HISTOGRAM_JSON_VERSION: u32 = 1;
+
+/// Stable JSON representation of [PointHistogram] (see [PointHistogram::to_json]).
+///
+/// Unlike derived [PointHistogram] serialization this layout does not depend on
+/// private fields and is versioned, so it can be used for archived spectra.
+/// Channel keys are 1-based (same as `ch N` columns in [PointHistogram::to_csv]).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PointHistogramJson {
+    pub version: u32,
+    pub range: [f32; 2],
+    pub bins: usize,
+    pub step: f32,
+    pub x: Vec<f32>,
+    pub channels: BTreeMap<u8, Vec<f32>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PointHistogram {
     pub x: Vec<f32>,
@@ -36,6 +54,58 @@ impl From<HistogramParams> for PointHistogram {
     }
 }
 
+impl From<&PointHistogram> for PointHistogramJson {
+    fn from(histogram: &PointHistogram) -> Self {
+        Self {
+            version: HISTOGRAM_JSON_VERSION,
+            range: [histogram.range.start, histogram.range.end],
+            bins: histogram.bins,
+            step: histogram.step,
+            x: histogram.x.clone(),
+            channels: histogram
+                .channels
+                .iter()
+                .map(|(ch_num, y)| (ch_num + 1, y.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<PointHistogramJson> for PointHistogram {
+    type Error = String;
+
+    fn try_from(json: PointHistogramJson) -> Result<Self, Self::Error> {
+        if json.version != HISTOGRAM_JSON_VERSION {
+            return Err(format!("unsupported histogram version: {}", json.version));
+        }
+        if json.x.len() != json.bins {
+            return Err(format!("x length {} != bins {}", json.x.len(), json.bins));
+        }
+
+        let channels = json
+            .channels
+            .into_iter()
+            .map(|(ch_num, y)| {
+                if ch_num == 0 {
+                    Err("channel numbers are 1-based".to_owned())
+                } else if y.len() != json.bins {
+                    Err(format!("ch {ch_num} length {} != bins {}", y.len(), json.bins))
+                } else {
+                    Ok((ch_num - 1, y))
+                }
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+
+        Ok(PointHistogram {
+            x: json.x,
+            channels,
+            step: json.step,
+            bins: json.bins,
+            range: json.range[0]..json.range[1],
+        })
+    }
+}
+
 // TODO: change to generic constant channel histogram
 impl PointHistogram {
     /// Constructor based on range + bins number
@@ -184,6 +254,78 @@ impl PointHistogram {
         data
     }
 
+    /// Restore histogram from [to_csv](PointHistogram::to_csv) output.
+    ///
+    /// Binning is restored from bin centers, so histogram must contain at least two bins.
+    /// `separator` must be the same as used for export (and must not be a space,
+    /// because it is a part of `ch N` header).
+    pub fn from_csv(data: &str, separator: char) -> Result<Self, String> {
+        let mut lines = data.lines().filter(|line| !line.trim().is_empty());
+
+        let header = lines.next().ok_or("csv is empty")?;
+        let mut columns = header.split(separator);
+        if columns.next().map(str::trim) != Some("bin") {
+            return Err(format!("unexpected csv header: {header:?}"));
+        }
+        let ch_nums = columns
+            .filter(|column| !column.trim().is_empty())
+            .map(|column| {
+                column
+                    .trim()
+                    .strip_prefix("ch ")
+                    .and_then(|ch_num| ch_num.trim().parse::<u8>().ok())
+                    .filter(|ch_num| *ch_num > 0)
+                    .map(|ch_num| ch_num - 1)
+                    .ok_or_else(|| format!("unexpected channel column: {column:?}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut x = vec![];
+        let mut channels = vec![vec![]; ch_nums.len()];
+
+        for (row_idx, line) in lines.enumerate() {
+            let mut cells = line.split(separator).map(|cell| {
+                cell.trim()
+                    .parse::<f32>()
+                    .map_err(|err| format!("row {}: {err} ({cell:?})", row_idx + 1))
+            });
+
+            x.push(cells.next().ok_or_else(|| format!("row {} is empty", row_idx + 1))??);
+            for channel in channels.iter_mut() {
+                channel.push(
+                    cells
+                        .next()
+                        .ok_or_else(|| format!("row {}: not enough columns", row_idx + 1))??,
+                );
+            }
+        }
+
+        if x.len() < 2 {
+            return Err("at least two bins are required to restore binning".to_owned());
+        }
+
+        let bins = x.len();
+        let step = (x[bins - 1] - x[0]) / (bins - 1) as f32;
+        let start = x[0] - step / 2.0;
+
+        let mut histogram = PointHistogram::new(start..(start + step * bins as f32), bins);
+        histogram.channels = ch_nums.into_iter().zip(channels).collect();
+
+        Ok(histogram)
+    }
+
+    /// Serialize histogram into stable JSON (see [PointHistogramJson]).
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&PointHistogramJson::from(self)).unwrap()
+    }
+
+    /// Restore histogram from [to_json](PointHistogram::to_json) output.
+    pub fn from_json(data: &str) -> Result<Self, String> {
+        serde_json::from_str::<PointHistogramJson>(data)
+            .map_err(|err| err.to_string())
+            .and_then(PointHistogram::try_from)
+    }
+
     #[cfg(feature = "egui")]
     fn build_egui_hist(&self, y: &[f32]) -> Vec<[f64; 2]> {
         y.iter()
@@ -288,3 +430,84 @@ impl PointHistogram {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram() -> PointHistogram {
+        let mut histogram = PointHistogram::new(0.0..40.0, 20);
+        for (ch_num, amplitude) in [(0, 1.5), (0, 1.7), (0, 20.0), (2, 39.0), (6, 10.1)] {
+            histogram.add(ch_num, amplitude);
+        }
+        histogram
+    }
+
+    fn assert_same_binning(restored: &PointHistogram, histogram: &PointHistogram) {
+        assert_eq!(restored.bins, histogram.bins);
+        assert!((restored.step - histogram.step).abs() < 1e-4);
+        assert!((restored.range.start - histogram.range.start).abs() < 1e-3);
+        assert!((restored.range.end - histogram.range.end).abs() < 1e-3);
+        for (x_restored, x) in restored.x.iter().zip(&histogram.x) {
+            assert!((x_restored - x).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let histogram = histogram();
+        for separator in [',', ';', '\t', '|'] {
+            let restored =
+                PointHistogram::from_csv(&histogram.to_csv(separator), separator).unwrap();
+            assert_same_binning(&restored, &histogram);
+            assert_eq!(restored.channels, histogram.channels);
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let histogram = histogram();
+        let restored = PointHistogram::from_json(&histogram.to_json()).unwrap();
+        assert_eq!(restored.x, histogram.x);
+        assert_eq!(restored.step, histogram.step);
+        assert_eq!(restored.bins, histogram.bins);
+        assert_eq!(restored.range, histogram.range);
+        assert_eq!(restored.channels, histogram.channels);
+    }
+
+    #[test]
+    fn csv_errors() {
+        assert!(PointHistogram::from_csv("", ',').is_err());
+        assert!(PointHistogram::from_csv("x,ch 1,\n0.5,1,\n1.5,2,\n", ',').is_err());
+        assert!(PointHistogram::from_csv("bin,ch 0,\n0.5,1,\n1.5,2,\n", ',').is_err());
+        assert!(PointHistogram::from_csv("bin,channel,\n0.5,1,\n1.5,2,\n", ',').is_err());
+        // malformed rows
+        assert!(PointHistogram::from_csv("bin,ch 1,\n0.5,abc,\n1.5,2,\n", ',').is_err());
+        assert!(PointHistogram::from_csv("bin,ch 1,ch 2,\n0.5,1,\n1.5,2,3,\n", ',').is_err());
+        // single bin
+        assert!(PointHistogram::from_csv("bin,ch 1,\n0.5,1,\n", ',').is_err());
+    }
+
+    #[test]
+    fn json_errors() {
+        let json = PointHistogramJson::from(&histogram());
+
+        let mut wrong_version = json.clone();
+        wrong_version.version += 1;
+        assert!(PointHistogram::try_from(wrong_version).is_err());
+
+        let mut wrong_x = json.clone();
+        wrong_x.x.pop();
+        assert!(PointHistogram::try_from(wrong_x).is_err());
+
+        let mut wrong_channel = json.clone();
+        wrong_channel.channels.get_mut(&1).unwrap().push(0.0);
+        assert!(PointHistogram::try_from(wrong_channel).is_err());
+
+        let mut zero_channel = json.clone();
+        zero_channel.channels.insert(0, vec![0.0; json.bins]);
+        assert!(PointHistogram::try_from(zero_channel).is_err());
+
+        assert!(PointHistogram::from_json("{\"version\": 1}").is_err());
+    }
+}
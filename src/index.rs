@@ -0,0 +1,347 @@
+//! # Index
+//! Local metadata index of numass storage (native only).
+//!
+//! [MetaIndex] scans storage root once, caches the main fields of each point [NumassMeta]
+//! in a local JSON file and then updates incrementally (only files with changed modified time are reread).
+//! Points can be selected with [PointFilter] without opening every file:
+//! ```no_run
+//! # use std::path::Path;
+//! # use processing::index::{MetaIndex, PointFilter};
+//! let index = MetaIndex::open(Path::new("/data/numass-storage"), Path::new("index.json")).unwrap();
+//! let points = index.query(&PointFilter {
+//!     hv: Some(13_999.0..14_001.0),
+//!     acquisition_time: Some(30_000_000_001..u64::MAX),
+//!     path_prefix: Some("/data/numass-storage/2024_03".into()),
+//!     ..Default::default()
+//! });
+//! ```
+use std::{
+    collections::BTreeMap,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::NaiveDateTime;
+use numass::{ExternalMeta, NumassMeta, Reply};
+use serde::{Deserialize, Serialize};
+
+use crate::{hierarchy::Point, storage::load_meta_sync};
+
+/// Cached point metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PointRecord {
+    /// file modified time at the moment of indexing
+    pub modified: SystemTime,
+    pub hv: Option<f32>,
+    pub start_time: Option<NaiveDateTime>,
+    /// acquisition time in nanoseconds
+    pub acquisition_time: Option<u64>,
+    pub external_meta: Option<ExternalMeta>,
+}
+
+impl PointRecord {
+    fn new(modified: SystemTime, meta: Option<NumassMeta>) -> Self {
+        if let Some(NumassMeta::Reply(Reply::AcquirePoint {
+            acquisition_time,
+            start_time,
+            external_meta,
+            ..
+        })) = meta
+        {
+            Self {
+                modified,
+                hv: external_meta.as_ref().and_then(|meta| meta.hv1_value),
+                start_time: Some(start_time),
+                acquisition_time: Some((acquisition_time * 1e9) as u64),
+                external_meta,
+            }
+        } else {
+            Self {
+                modified,
+                hv: None,
+                start_time: None,
+                acquisition_time: None,
+                external_meta: None,
+            }
+        }
+    }
+}
+
+/// Query for [MetaIndex::query]. All set conditions must match, `None` matches anything.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PointFilter {
+    pub hv: Option<Range<f32>>,
+    pub start_time: Option<Range<NaiveDateTime>>,
+    /// acquisition time in nanoseconds
+    pub acquisition_time: Option<Range<u64>>,
+    /// select only points under this path (e.g. run or set directory)
+    pub path_prefix: Option<PathBuf>,
+}
+
+impl PointFilter {
+    pub fn matches(&self, path: &Path, record: &PointRecord) -> bool {
+        fn in_range<T: PartialOrd>(range: &Option<Range<T>>, value: &Option<T>) -> bool {
+            match (range, value) {
+                (None, _) => true,
+                (Some(range), Some(value)) => range.contains(value),
+                (Some(_), None) => false,
+            }
+        }
+
+        in_range(&self.hv, &record.hv)
+            && in_range(&self.start_time, &record.start_time)
+            && in_range(&self.acquisition_time, &record.acquisition_time)
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// Result of [MetaIndex::update].
+#[derive(Debug, Default)]
+pub struct IndexUpdate {
+    /// number of (re)indexed points
+    pub updated: usize,
+    /// paths that failed to be read (their previous records are kept)
+    pub errors: Vec<(PathBuf, io::Error)>,
+}
+
+/// Metadata index of the storage (see [module docs](crate::index)).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetaIndex {
+    pub root: PathBuf,
+    pub points: BTreeMap<PathBuf, PointRecord>,
+}
+
+impl MetaIndex {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// Load index from `cache` (or create new one if cache is missing or belongs to another root),
+    /// update it and save back.
+    /// Fails only if the cache can't be saved (errors of the update are skipped, see [MetaIndex::update]).
+    pub fn open(root: &Path, cache: &Path) -> io::Result<Self> {
+        let mut index = match Self::load(cache) {
+            Ok(index) if index.root == root => index,
+            _ => Self::new(root.to_owned()),
+        };
+        index.update();
+        index.save(cache)?;
+        Ok(index)
+    }
+
+    pub fn load(cache: &Path) -> io::Result<Self> {
+        let file = io::BufReader::new(std::fs::File::open(cache)?);
+        serde_json::from_reader(file).map_err(io::Error::from)
+    }
+
+    pub fn save(&self, cache: &Path) -> io::Result<()> {
+        let file = io::BufWriter::new(std::fs::File::create(cache)?);
+        serde_json::to_writer(file, self).map_err(io::Error::from)
+    }
+
+    /// Rescan the storage: index new and modified points, remove deleted ones.
+    ///
+    /// Errors do not stop the rescan: failed entries keep their previous records
+    /// (e.g. point that is still being written is reindexed on the next update).
+    pub fn update(&mut self) -> IndexUpdate {
+        let mut found = BTreeMap::new();
+        // points under these paths are kept as is
+        let mut failed = vec![];
+        let mut errors = vec![];
+
+        let mut dirs = vec![self.root.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            let read_dir = match std::fs::read_dir(&dir) {
+                Ok(read_dir) => read_dir,
+                Err(err) => {
+                    failed.push(dir.clone());
+                    errors.push((dir, err));
+                    continue;
+                }
+            };
+            for entry in read_dir {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        failed.push(dir.clone());
+                        errors.push((dir.clone(), err));
+                        continue;
+                    }
+                };
+                let path = entry.path();
+                let meta = match entry.metadata() {
+                    Ok(meta) => meta,
+                    Err(err) => {
+                        failed.push(path.clone());
+                        errors.push((path, err));
+                        continue;
+                    }
+                };
+                if meta.is_dir() {
+                    dirs.push(path);
+                } else if meta.is_file() && Point::from_path(path.to_owned()).is_some() {
+                    match meta.modified() {
+                        Ok(modified) => {
+                            found.insert(path, modified);
+                        }
+                        Err(err) => {
+                            failed.push(path.clone());
+                            errors.push((path, err));
+                        }
+                    }
+                }
+            }
+        }
+
+        self.points.retain(|path, _| {
+            found.contains_key(path) || failed.iter().any(|failed| path.starts_with(failed))
+        });
+
+        let mut updated = 0;
+        for (path, modified) in found {
+            let outdated = self
+                .points
+                .get(&path)
+                .is_none_or(|record| record.modified != modified);
+            if outdated {
+                match load_meta_sync(&path) {
+                    Ok(meta) => {
+                        self.points.insert(path, PointRecord::new(modified, meta));
+                        updated += 1;
+                    }
+                    Err(err) => errors.push((path, err)),
+                }
+            }
+        }
+
+        IndexUpdate { updated, errors }
+    }
+
+    /// Paths of points matching the filter (in natural order).
+    pub fn query(&self, filter: &PointFilter) -> Vec<PathBuf> {
+        let mut points = self
+            .points
+            .iter()
+            .filter(|(path, record)| filter.matches(path, record))
+            .map(|(path, _)| path.to_owned())
+            .collect::<Vec<_>>();
+        points.sort_by(|first, second| {
+            natord::compare(
+                first.to_str().unwrap_or_default(),
+                second.to_str().unwrap_or_default(),
+            )
+        });
+        points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Record with acquisition time in seconds.
+    fn record(hv: Option<f32>, acquisition_time: u64) -> PointRecord {
+        PointRecord {
+            modified: SystemTime::UNIX_EPOCH,
+            hv,
+            start_time: None,
+            acquisition_time: Some(acquisition_time * 1_000_000_000),
+            external_meta: None,
+        }
+    }
+
+    #[test]
+    fn query_hv_and_acquisition_time() {
+        let mut index = MetaIndex::new(PathBuf::from("/data"));
+        for (path, hv, acquisition_time) in [
+            ("/data/2024_03/set_1/p0(60s)(HV1=14000)", Some(14_000.0), 60),
+            ("/data/2024_03/set_1/p1(60s)(HV1=14500)", Some(14_500.0), 60),
+            ("/data/2024_03/set_1/p2(30s)(HV1=14000)", Some(14_000.0), 30),
+            ("/data/2024_03/set_1/p3(60s)", None, 60),
+            (
+                "/data/2024_03/set_2/p10(60s)(HV1=14000)",
+                Some(14_000.0),
+                60,
+            ),
+            ("/data/2024_03/set_2/p9(60s)(HV1=14000)", Some(14_000.0), 60),
+            ("/data/2024_02/set_1/p0(60s)(HV1=14000)", Some(14_000.0), 60),
+        ] {
+            index
+                .points
+                .insert(PathBuf::from(path), record(hv, acquisition_time));
+        }
+
+        let points = index.query(&PointFilter {
+            hv: Some(13_999.0..14_001.0),
+            acquisition_time: Some(30_000_000_001..u64::MAX),
+            path_prefix: Some("/data/2024_03".into()),
+            ..Default::default()
+        });
+        assert_eq!(
+            points,
+            [
+                "/data/2024_03/set_1/p0(60s)(HV1=14000)",
+                "/data/2024_03/set_2/p9(60s)(HV1=14000)",
+                "/data/2024_03/set_2/p10(60s)(HV1=14000)",
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            index.query(&PointFilter::default()).len(),
+            index.points.len()
+        );
+    }
+
+    #[test]
+    fn incremental_update() {
+        let root = std::env::temp_dir().join(format!("processing-index-{}", std::process::id()));
+        let set = root.join("set_1");
+        std::fs::create_dir_all(&set).unwrap();
+        let first = set.join("p0(30s)(HV1=14000)");
+        let second = set.join("p1(30s)(HV1=14500)");
+        for path in [&first, &second, &set.join("notes.txt")] {
+            std::fs::write(path, b"not an envelope").unwrap();
+        }
+
+        let mut index = MetaIndex::new(root.clone());
+        let update = index.update();
+        assert!(update.errors.is_empty(), "{:?}", update.errors);
+        assert_eq!(update.updated, 2);
+        assert_eq!(index.points.len(), 2);
+        assert_eq!(index.update().updated, 0);
+
+        // modified time change
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&second)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(index.update().updated, 1);
+        assert_eq!(index.points[&second].modified, modified);
+
+        // deletion
+        std::fs::remove_file(&first).unwrap();
+        assert_eq!(index.update().updated, 0);
+        assert_eq!(index.points.keys().collect::<Vec<_>>(), vec![&second]);
+
+        // unreadable root keeps previous records
+        std::fs::remove_dir_all(&root).unwrap();
+        let update = index.update();
+        assert_eq!(update.updated, 0);
+        assert_eq!(update.errors.len(), 1);
+        assert_eq!(update.errors[0].0, root);
+        assert!(index.points.contains_key(&second));
+    }
+}
@@ -0,0 +1,408 @@
+//! # Jobs
+//! Job-based remote processing protocol.
+//!
+//! Unlike `api/process` (one blocking request per point) a job processes a list of points in background,
+//! so the client can poll progress, cancel the job and fetch results when it is done.
+//!
+//! Endpoints (relative to the server origin):
+//! - `POST api/jobs` - submit [JobRequest] (json), returns [JobId] (json),
+//!   requests with points outside of the server storage are rejected
+//! - `GET api/jobs/{id}` - [JobProgress] (json)
+//! - `POST api/jobs/{id}/cancel` - cancel job, returns [JobProgress] (json)
+//! - `GET api/jobs/{id}/results` - [JobResults] (msgpack), available when job is not running
+//!
+//! Server side is implemented by [JobManager] (native only), client side by [HttpBackend] methods.
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    postprocess::PostProcessParams, preprocess::Preprocess, process::ProcessParams,
+    types::NumassEvents,
+};
+
+#[cfg(any(target_arch = "wasm32", feature = "remote"))]
+use crate::backend::HttpBackend;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::{JobManager, JOB_RETENTION};
+
+/// Jobs API prefix.
+pub const JOBS_API: &str = "api/jobs";
+
+pub type JobId = u64;
+
+/// Result of a single point: `Ok(None)` if the file is not a point or the job was cancelled before it,
+/// `Err` with description if processing failed (missing file, corrupted data, etc.).
+pub type PointResult = Result<Option<(NumassEvents, Preprocess)>, String>;
+
+/// Processing results in the order of [JobRequest::points].
+pub type JobResults = Vec<(PathBuf, PointResult)>;
+
+/// Job submission: same params are applied to every point.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JobRequest {
+    pub process: ProcessParams,
+    pub postprocess: Option<PostProcessParams>,
+    pub points: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    /// all points are processed (some of them may have errors, see [PointResult])
+    Finished,
+    Cancelled,
+    /// job was interrupted by an unexpected error, results are incomplete
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct JobProgress {
+    pub id: JobId,
+    pub state: JobState,
+    /// number of processed points
+    pub done: usize,
+    /// total number of points in the job
+    pub total: usize,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod server {
+    use std::{
+        collections::BTreeMap,
+        panic::{catch_unwind, AssertUnwindSafe},
+        path::{Component, Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc, Mutex, MutexGuard, PoisonError,
+        },
+        time::{Duration, Instant},
+    };
+
+    use super::{JobId, JobProgress, JobRequest, JobResults, JobState, PointResult};
+    use crate::storage::process_point_mmap;
+
+    /// Default time finished jobs are kept in the registry (see [JobManager::with_retention]).
+    pub const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+    struct Job {
+        progress: JobProgress,
+        results: JobResults,
+        cancel: Arc<AtomicBool>,
+        /// time when the job stopped running
+        finished: Option<Instant>,
+    }
+
+    type Jobs = Arc<Mutex<BTreeMap<JobId, Job>>>;
+
+    fn lock(jobs: &Jobs) -> MutexGuard<'_, BTreeMap<JobId, Job>> {
+        jobs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Server-side job registry.
+    /// Jobs are processed on tokio blocking threads (requires tokio runtime), point by point.
+    /// Cancellation is checked between points.
+    ///
+    /// Only points inside the storage root are processed. Finished jobs are removed after
+    /// the retention time even if their results were not taken.
+    #[derive(Clone)]
+    pub struct JobManager {
+        root: PathBuf,
+        retention: Duration,
+        jobs: Jobs,
+        next_id: Arc<AtomicU64>,
+    }
+
+    impl JobManager {
+        /// Create registry for the storage at `root` (must exist).
+        pub fn new(root: &Path) -> std::io::Result<Self> {
+            Ok(Self {
+                root: root.canonicalize()?,
+                retention: JOB_RETENTION,
+                jobs: Arc::new(Mutex::new(BTreeMap::new())),
+                next_id: Arc::new(AtomicU64::new(0)),
+            })
+        }
+
+        /// Set time finished jobs are kept in the registry.
+        pub fn with_retention(mut self, retention: Duration) -> Self {
+            self.retention = retention;
+            self
+        }
+
+        /// Check that `path` is inside the storage root.
+        /// Symlinks are resolved for existing paths, other paths must not contain `..`.
+        fn is_allowed(&self, path: &Path) -> bool {
+            match path.canonicalize() {
+                Ok(path) => path.starts_with(&self.root),
+                Err(_) => {
+                    path.starts_with(&self.root)
+                        && path.components().all(|part| part != Component::ParentDir)
+                }
+            }
+        }
+
+        /// Remove finished jobs older than retention time.
+        fn evict(&self, jobs: &mut BTreeMap<JobId, Job>) {
+            jobs.retain(|_, job| {
+                job.finished
+                    .is_none_or(|finished| finished.elapsed() < self.retention)
+            });
+        }
+
+        /// Start processing and return job id.
+        /// Returns error if any of the points is outside the storage root.
+        pub fn submit(&self, request: JobRequest) -> Result<JobId, String> {
+            if let Some(path) = request.points.iter().find(|path| !self.is_allowed(path)) {
+                return Err(format!("{path:?} is outside of the storage root"));
+            }
+
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let cancel = Arc::new(AtomicBool::new(false));
+
+            {
+                let mut jobs = lock(&self.jobs);
+                self.evict(&mut jobs);
+                jobs.insert(
+                    id,
+                    Job {
+                        progress: JobProgress {
+                            id,
+                            state: JobState::Running,
+                            done: 0,
+                            total: request.points.len(),
+                        },
+                        results: vec![],
+                        cancel: cancel.clone(),
+                        finished: None,
+                    },
+                );
+            }
+
+            let manager = self.clone();
+            tokio::task::spawn_blocking(move || {
+                // marks the job as failed if the loop below exits abnormally
+                let guard = FailGuard {
+                    jobs: manager.jobs.clone(),
+                    id,
+                };
+
+                let JobRequest {
+                    process,
+                    postprocess,
+                    points,
+                } = request;
+
+                for filepath in points {
+                    let processed: PointResult = if cancel.load(Ordering::Relaxed) {
+                        Ok(None)
+                    } else if !manager.is_allowed(&filepath) {
+                        Err(format!("{filepath:?} is outside of the storage root"))
+                    } else {
+                        catch_unwind(AssertUnwindSafe(|| {
+                            process_point_mmap(&filepath, &process, postprocess.as_ref())
+                        }))
+                        .map_err(panic_message)
+                        .and_then(|processed| processed.map_err(|err| err.to_string()))
+                        .map(|(_, processed)| processed)
+                    };
+
+                    let mut jobs = lock(&manager.jobs);
+                    let Some(job) = jobs.get_mut(&id) else {
+                        // job was removed by client
+                        return;
+                    };
+                    job.results.push((filepath, processed));
+                    if !cancel.load(Ordering::Relaxed) {
+                        job.progress.done += 1;
+                    }
+                }
+
+                let state = if cancel.load(Ordering::Relaxed) {
+                    JobState::Cancelled
+                } else {
+                    JobState::Finished
+                };
+                guard.finish(state);
+            });
+
+            Ok(id)
+        }
+
+        pub fn progress(&self, id: JobId) -> Option<JobProgress> {
+            let mut jobs = lock(&self.jobs);
+            self.evict(&mut jobs);
+            jobs.get(&id).map(|job| job.progress.to_owned())
+        }
+
+        /// Request cancellation. Job state changes to [JobState::Cancelled]
+        /// after the point that is being processed now.
+        pub fn cancel(&self, id: JobId) -> Option<JobProgress> {
+            let jobs = lock(&self.jobs);
+            let job = jobs.get(&id)?;
+            job.cancel.store(true, Ordering::Relaxed);
+            Some(job.progress.to_owned())
+        }
+
+        /// Take results of finished (cancelled, failed) job. Job is removed from the registry.
+        /// Returns `None` if job is unknown or still running.
+        pub fn results(&self, id: JobId) -> Option<JobResults> {
+            let mut jobs = lock(&self.jobs);
+            if jobs.get(&id)?.progress.state == JobState::Running {
+                return None;
+            }
+            jobs.remove(&id).map(|job| job.results)
+        }
+    }
+
+    /// Moves the job out of [JobState::Running] when dropped:
+    /// to the state passed to [finish](FailGuard::finish) or to [JobState::Failed] (e.g. on panic).
+    struct FailGuard {
+        jobs: Jobs,
+        id: JobId,
+    }
+
+    impl FailGuard {
+        fn finish(self, state: JobState) {
+            self.set_state(state);
+        }
+
+        fn set_state(&self, state: JobState) {
+            if let Some(job) = lock(&self.jobs).get_mut(&self.id) {
+                if job.progress.state == JobState::Running {
+                    job.progress.state = state;
+                    job.finished = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    impl Drop for FailGuard {
+        fn drop(&mut self) {
+            self.set_state(JobState::Failed);
+        }
+    }
+
+    fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            format!("processing panicked: {message}")
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            format!("processing panicked: {message}")
+        } else {
+            "processing panicked".to_owned()
+        }
+    }
+}
+
+#[cfg(any(target_arch = "wasm32", feature = "remote"))]
+impl HttpBackend {
+    fn job_url(&self, suffix: &str) -> String {
+        format!("{}/{JOBS_API}{suffix}", self.base_url)
+    }
+
+    pub async fn submit_job(&self, request: &JobRequest) -> Option<JobId> {
+        let payload = self.post(&self.job_url(""), request).await?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    pub async fn job_progress(&self, id: JobId) -> Option<JobProgress> {
+        let payload = self.get(&self.job_url(&format!("/{id}"))).await?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    pub async fn cancel_job(&self, id: JobId) -> Option<JobProgress> {
+        let payload = self
+            .post(&self.job_url(&format!("/{id}/cancel")), &())
+            .await?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    pub async fn job_results(&self, id: JobId) -> Option<JobResults> {
+        let payload = self.get(&self.job_url(&format!("/{id}/results"))).await?;
+        rmp_serde::from_slice(&payload).ok()
+    }
+
+    /// Poll job progress every `interval` until it stops running.
+    /// `on_progress` is called after every poll (e.g. to update progress bar).
+    pub async fn wait_job(
+        &self,
+        id: JobId,
+        interval: std::time::Duration,
+        mut on_progress: impl FnMut(&JobProgress),
+    ) -> Option<JobProgress> {
+        loop {
+            let progress = self.job_progress(id).await?;
+            on_progress(&progress);
+            if progress.state != JobState::Running {
+                return Some(progress);
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            tokio::time::sleep(interval).await;
+            #[cfg(target_arch = "wasm32")]
+            gloo::timers::future::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn wait(manager: &JobManager, id: JobId) -> JobProgress {
+        loop {
+            let progress = manager.progress(id).unwrap();
+            if progress.state != JobState::Running {
+                return progress;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn request(points: Vec<PathBuf>) -> JobRequest {
+        JobRequest {
+            process: ProcessParams::default(),
+            postprocess: None,
+            points,
+        }
+    }
+
+    #[tokio::test]
+    async fn job_errors() {
+        let root = std::env::temp_dir().join(format!("processing-jobs-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("corrupted"), b"not a point").unwrap();
+        let manager = JobManager::new(&root).unwrap();
+
+        for outside in [root.join("../corrupted"), root.parent().unwrap().to_owned()] {
+            assert!(manager.submit(request(vec![outside])).is_err());
+        }
+
+        let points = vec![root.join("corrupted"), root.join("missing")];
+        let id = manager.submit(request(points.clone())).unwrap();
+        let progress = wait(&manager, id).await;
+        assert_eq!(progress.state, JobState::Finished);
+        assert_eq!(progress.done, 2);
+
+        let results = manager.results(id).unwrap();
+        assert_eq!(
+            results.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            points.iter().collect::<Vec<_>>()
+        );
+        assert!(results.iter().all(|(_, result)| result.is_err()));
+        assert!(manager.progress(id).is_none());
+
+        // finished jobs are evicted after retention time
+        let manager = manager.with_retention(Duration::ZERO);
+        let id = manager.submit(request(vec![root.join("missing")])).unwrap();
+        while manager.progress(id).is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(manager.results(id).is_none());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
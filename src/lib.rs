@@ -1,13 +1,27 @@
 pub extern crate numass;
+pub mod backend;
+pub mod coincidence;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod export;
+pub mod hierarchy;
 pub mod histogram;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod index;
+pub mod jobs;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mmap;
 pub mod viewer; // TODO: move to numass-processing with viewer feature
 
+pub mod pipeline;
 pub mod postprocess;
 pub mod preprocess;
 pub mod process;
+pub mod reader;
 pub mod storage;
+pub mod timing;
 pub mod types;
 pub mod utils;
+pub mod watch;
 #[cfg(feature = "egui")]
 pub mod widgets;
 
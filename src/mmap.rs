@@ -0,0 +1,162 @@
+//! # Mmap
+//! Memory-mapped point loading (native only).
+//!
+//! [MappedPoint] maps the `.df` file into memory and walks protobuf wire format in place,
+//! so waveforms are borrowed from the mapping instead of being copied
+//! into `rsb_event::Point` (see [waveform_from_bytes] for alignment notes).
+use std::{fs::File, io, ops::Range, path::Path};
+
+use memmap2::Mmap;
+use numass::NumassMeta;
+
+use crate::{
+    preprocess::waveform_from_bytes,
+    reader::{
+        read_tag, read_varint, skip_field, take_len, BLOCK_FRAMES, CHANNEL_BLOCKS, CHANNEL_ID,
+        FRAME_DATA, FRAME_TIME, POINT_CHANNELS, WIRE_LEN, WIRE_VARINT,
+    },
+    types::NumassWaveformsCow,
+    utils::correct_frame_time,
+};
+
+/// Point file mapped into memory.
+pub struct MappedPoint {
+    mmap: Mmap,
+    meta: NumassMeta,
+    /// protobuf message range in the mapping
+    data: Range<usize>,
+}
+
+impl MappedPoint {
+    /// Map point file and read its meta.
+    pub fn open(filepath: &Path) -> io::Result<Self> {
+        let file = File::open(filepath)?;
+        // SAFETY: point files are not modified after acquisition,
+        // modification of the file while it is mapped is UB
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let mut cursor = &mmap[..];
+        let (_, meta) = dataforge::read_df_header_and_meta_sync::<NumassMeta>(&mut cursor)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err:?}")))?;
+        let data = (mmap.len() - cursor.len())..mmap.len();
+
+        Ok(Self { mmap, meta, data })
+    }
+
+    pub fn meta(&self) -> &NumassMeta {
+        &self.meta
+    }
+
+    /// Extract waveforms from the mapping (same layout as [extract_waveforms](crate::preprocess::extract_waveforms)).
+    pub fn waveforms(&self) -> io::Result<NumassWaveformsCow<'_>> {
+        parse_point(&self.mmap[self.data.clone()])
+    }
+}
+
+/// Parse encoded `rsb_event::Point` into waveforms borrowed from `point`.
+/// Fails on invalid wire format or frame data (same as [extract_waveforms](crate::preprocess::extract_waveforms)).
+fn parse_point(mut point: &[u8]) -> io::Result<NumassWaveformsCow<'_>> {
+    let mut waveforms = NumassWaveformsCow::new();
+
+    while !point.is_empty() {
+        let (field, wire) = read_tag(&mut point)?;
+        if field != POINT_CHANNELS || wire != WIRE_LEN {
+            skip_field(&mut point, wire)?;
+            continue;
+        }
+
+        let mut channel = take_len(&mut point)?;
+        let mut channel_id = 0;
+        let mut frames = vec![];
+        while !channel.is_empty() {
+            let (field, wire) = read_tag(&mut channel)?;
+            if field == CHANNEL_ID && wire == WIRE_VARINT {
+                channel_id = read_varint(&mut channel)? as u8;
+            } else if field == CHANNEL_BLOCKS && wire == WIRE_LEN {
+                let mut block = take_len(&mut channel)?;
+                while !block.is_empty() {
+                    let (field, wire) = read_tag(&mut block)?;
+                    if field == BLOCK_FRAMES && wire == WIRE_LEN {
+                        frames.push(parse_frame(take_len(&mut block)?)?);
+                    } else {
+                        skip_field(&mut block, wire)?;
+                    }
+                }
+            } else {
+                skip_field(&mut channel, wire)?;
+            }
+        }
+
+        // channel id can be encoded after blocks, so it is applied at the end
+        for (time, data) in frames {
+            let time = correct_frame_time(time);
+            let waveform = waveform_from_bytes(data).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid frame {time} of channel {channel_id}: {err}"),
+                )
+            })?;
+            waveforms
+                .entry(time)
+                .or_default()
+                .insert(channel_id, waveform);
+        }
+    }
+
+    Ok(waveforms)
+}
+
+/// Parse `rsb_event::Point.Channel.Block.Frame` as (time, data).
+fn parse_frame(mut frame: &[u8]) -> io::Result<(u64, &[u8])> {
+    let mut time = 0;
+    let mut data: &[u8] = &[];
+    while !frame.is_empty() {
+        let (field, wire) = read_tag(&mut frame)?;
+        if field == FRAME_TIME && wire == WIRE_VARINT {
+            time = read_varint(&mut frame)?;
+        } else if field == FRAME_DATA && wire == WIRE_LEN {
+            data = take_len(&mut frame)?;
+        } else {
+            skip_field(&mut frame, wire)?;
+        }
+    }
+    Ok((time, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::Message;
+
+    use super::*;
+    use crate::{preprocess::extract_waveforms, reader::tests::point};
+
+    #[test]
+    fn parse_point_matches_extract_waveforms() {
+        let point = point();
+        let data = point.write_to_bytes().unwrap();
+        assert_eq!(
+            parse_point(&data).unwrap(),
+            extract_waveforms(&point).unwrap()
+        );
+    }
+
+    #[test]
+    fn odd_frame_fails_both_paths() {
+        let mut point = point();
+        point.channels[1].blocks[2].frames[0].data.pop();
+        let data = point.write_to_bytes().unwrap();
+
+        let err = parse_point(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), extract_waveforms(&point).unwrap_err());
+    }
+
+    #[test]
+    fn truncated_point() {
+        let data = point().write_to_bytes().unwrap();
+        assert!(parse_point(&data[..data.len() - 1]).is_err());
+        for len in 0..data.len() {
+            let _ = parse_point(&data[..len]);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! # Pipeline
+//! Composable postprocessing.
+//!
+//! Postprocessing is described as a serializable list of [PostStage]s that are applied in order.
+//! [PostProcessParams] is a preset for the pipeline (see [PostProcessParams::to_stages]).
+//! Analysis-specific stages can be added with [CustomStage] trait:
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use processing::{pipeline::*, preprocess::Preprocess, types::NumassEvent};
+//! struct OddFrames;
+//!
+//! impl CustomStage for OddFrames {
+//!     fn process_frame(
+//!         &self,
+//!         time: u64,
+//!         events: Vec<NumassEvent>,
+//!         _: &Preprocess,
+//!     ) -> Option<(u64, Vec<NumassEvent>)> {
+//!         (time % 2 == 1).then_some((time, events))
+//!     }
+//! }
+//!
+//! let mut custom = CustomStages::new();
+//! custom.insert("odd_frames".to_owned(), Arc::new(OddFrames));
+//! let stages = vec![PostStage::CutBadBlocks, PostStage::Custom("odd_frames".to_owned())];
+//! ```
+use std::{collections::BTreeMap, ops::Range, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    postprocess::{
+        ignore_channels, merge_close_events, merge_close_frames, merge_splits, ChannelIgnore,
+        MergeWindow, PostProcessParams,
+    },
+    preprocess::Preprocess,
+    types::{EventOffset, FrameEvent, NumassEvent},
+};
+
+/// Stream of processed frames (sorted by time).
+pub type Frames<'a> = Box<dyn Iterator<Item = (u64, Vec<NumassEvent>)> + 'a>;
+
+/// Custom stages available for [PostStage::Custom] (by name).
+pub type CustomStages = BTreeMap<String, Arc<dyn CustomStage>>;
+
+/// User-defined postprocessing stage (applied frame by frame).
+pub trait CustomStage: Send + Sync {
+    /// Process single frame. Return `None` to drop the frame.
+    fn process_frame(
+        &self,
+        time: u64,
+        events: Vec<NumassEvent>,
+        preprocess: &Preprocess,
+    ) -> Option<(u64, Vec<NumassEvent>)>;
+}
+
+/// Postprocessing stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PostStage {
+    /// combine frames closer than `merge_len` (in ns) into one
+    MergeFrames { merge_len: u16 },
+    /// remove frames inside [bad_blocks](crate::preprocess::Preprocess::bad_blocks)
+    CutBadBlocks,
+    /// merge events split between channel 6 and its neighbours (time delta < `window`)
+    MergeSplits {
+        #[serde(default)]
+        window: MergeWindow,
+    },
+    /// merge events from neighbouring pixels into one (only inside `window` if set)
+    MergeCloseEvents {
+        ignore_borders: bool,
+        #[serde(default)]
+        window: Option<MergeWindow>,
+    },
+    /// remove events of ignored channels
+    IgnoreChannels(ChannelIgnore),
+    /// keep only events with amplitude inside the window (other frame events are kept)
+    AmplitudeWindow { range: Range<f32> },
+    /// keep only frames inside the time range (in ns from the point start)
+    TimeCut { range: Range<u64> },
+    /// keep only events of selected channels (other frame events are kept),
+    /// channels are 0-based indices as in [FrameEvent::Event] (`0` is ch1)
+    ChannelCut { channels: Vec<u8> },
+    /// remove events followed or preceded by another event in the same channel closer than `window` (in ns)
+    PileUpRejection { window: u16 },
+    /// stage from [CustomStages] (by name)
+    Custom(String),
+}
+
+impl PostStage {
+    /// Apply stage to the frames stream.
+    /// Fails only if [Custom](PostStage::Custom) stage is not found in `custom`.
+    pub fn apply<'a>(
+        self,
+        frames: Frames<'a>,
+        preprocess: &'a Preprocess,
+        custom: &CustomStages,
+    ) -> Result<Frames<'a>, String> {
+        Ok(match self {
+            PostStage::MergeFrames { merge_len } => Box::new(merge_close_frames(
+                frames,
+                merge_len,
+                preprocess.frame_len,
+            )),
+            PostStage::CutBadBlocks => Box::new(frames.filter(move |(time, _)| {
+                let curr_block = (time / preprocess.cutoff_bin_size) as usize;
+                !preprocess.bad_blocks.contains(&curr_block)
+            })),
+            PostStage::MergeSplits { window } => Box::new(frames.map(move |(time, events)| {
+                let events = merge_splits(
+                    events,
+                    window,
+                    #[cfg(feature = "egui")]
+                    &mut None,
+                );
+                (time, events)
+            })),
+            PostStage::MergeCloseEvents {
+                ignore_borders,
+                window,
+            } => {
+                Box::new(frames.map(move |(time, events)| {
+                    let events = merge_close_events(
+                        events,
+                        ignore_borders,
+                        window,
+                        #[cfg(feature = "egui")]
+                        None,
+                    );
+                    (time, events)
+                }))
+            }
+            PostStage::IgnoreChannels(ignored) => {
+                let ignored: [bool; 7] = ignored.into();
+                Box::new(frames.map(move |(time, mut events)| {
+                    ignore_channels(&ignored, &mut events);
+                    (time, events)
+                }))
+            }
+            PostStage::AmplitudeWindow { range } => {
+                Box::new(frames.map(move |(time, mut events)| {
+                    events.retain(|(_, event)| match event {
+                        FrameEvent::Event { amplitude, .. } => range.contains(amplitude),
+                        _ => true,
+                    });
+                    (time, events)
+                }))
+            }
+            PostStage::TimeCut { range } => {
+                Box::new(frames.filter(move |(time, _)| range.contains(time)))
+            }
+            PostStage::ChannelCut { channels } => {
+                Box::new(frames.map(move |(time, mut events)| {
+                    events.retain(|(_, event)| match event {
+                        FrameEvent::Event { channel, .. } => channels.contains(channel),
+                        _ => true,
+                    });
+                    (time, events)
+                }))
+            }
+            PostStage::PileUpRejection { window } => {
+                Box::new(frames.map(move |(time, events)| (time, reject_pile_up(events, window))))
+            }
+            PostStage::Custom(name) => {
+                let stage = custom
+                    .get(&name)
+                    .cloned()
+                    .ok_or(format!("custom stage {name} not found"))?;
+                Box::new(
+                    frames.filter_map(move |(time, events)| {
+                        stage.process_frame(time, events, preprocess)
+                    }),
+                )
+            }
+        })
+    }
+}
+
+/// Apply stages to the frames in order.
+pub fn run_pipeline<'a, I: Iterator<Item = (u64, Vec<NumassEvent>)> + 'a>(
+    frames: I,
+    preprocess: &'a Preprocess,
+    stages: Vec<PostStage>,
+    custom: &CustomStages,
+) -> Result<Frames<'a>, String> {
+    stages
+        .into_iter()
+        .try_fold(Box::new(frames) as Frames<'a>, |frames, stage| {
+            stage.apply(frames, preprocess, custom)
+        })
+}
+
+/// Remove events that have another event in the same channel closer than `window` (in ns).
+///
+/// Events are sorted by channel and offset, so it is enough to compare neighbours.
+fn reject_pile_up(events: Vec<NumassEvent>, window: u16) -> Vec<NumassEvent> {
+    let mut sorted = events
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, (offset, event))| match event {
+            FrameEvent::Event { channel, .. } => Some((*channel, *offset, idx)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    sorted.sort_unstable();
+
+    let mut piled_up = vec![false; events.len()];
+    for pair in sorted.windows(2) {
+        let ((channel, offset, idx), (other_channel, other_offset, other_idx)) = (pair[0], pair[1]);
+        if channel == other_channel && other_offset - offset < EventOffset::from(window) {
+            piled_up[idx] = true;
+            piled_up[other_idx] = true;
+        }
+    }
+
+    events
+        .into_iter()
+        .zip(piled_up)
+        .filter_map(|(event, piled_up)| (!piled_up).then_some(event))
+        .collect()
+}
+
+impl PostProcessParams {
+    /// Pipeline equivalent to these params (see [post_process_frames](crate::postprocess::post_process_frames)).
+    pub fn to_stages(&self) -> Vec<PostStage> {
+        let mut stages = vec![];
+        if let Some(merge_len) = self.merge_frames {
+            stages.push(PostStage::MergeFrames { merge_len });
+        }
+        if self.cut_bad_blocks {
+            stages.push(PostStage::CutBadBlocks);
+        }
+        if self.merge_splits_first {
+            stages.push(PostStage::MergeSplits {
+                window: self.split_window,
+            });
+        }
+        if self.merge_close_events {
+            stages.push(PostStage::MergeCloseEvents {
+                ignore_borders: self.ignore_borders,
+                window: self.merge_window,
+            });
+        }
+        if self.ignore_channels != ChannelIgnore::default() {
+            stages.push(PostStage::IgnoreChannels(self.ignore_channels));
+        }
+        stages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(offset: EventOffset, channel: u8) -> NumassEvent {
+        (
+            offset,
+            FrameEvent::Event {
+                channel,
+                amplitude: offset as f32,
+                size: 10,
+            },
+        )
+    }
+
+    #[test]
+    fn pile_up_rejection() {
+        let events = vec![
+            event(500, 0),
+            event(0, 0),
+            event(120, 1),
+            (50, FrameEvent::Reset { size: 10 }),
+            event(90, 0),
+            event(300, 1),
+            event(100, 2),
+            event(1000, 0),
+            event(1050, 0),
+            event(1149, 0),
+        ];
+        let offsets = reject_pile_up(events, 100)
+            .into_iter()
+            .map(|(offset, _)| offset)
+            .collect::<Vec<_>>();
+        // order is kept, chain 1000-1050-1149 is rejected entirely
+        assert_eq!(offsets, vec![500, 120, 50, 300, 100]);
+    }
+
+    #[test]
+    fn custom_stages_are_shareable() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&CustomStages::new());
+    }
+}
@@ -4,14 +4,13 @@
 //! see [params](crate::postprocess::PostProcessParams) for details.
 //!
 
-use std::collections::BTreeMap;
-
 use serde::{Deserialize, Serialize};
 
 use crate::{
     constants::DETECTOR_BORDERS,
-    preprocess::{Preprocess, CUTOFF_BIN_SIZE},
-    types::{FrameEvent, NumassEvent, NumassEvents},
+    pipeline::{run_pipeline, CustomStages},
+    preprocess::Preprocess,
+    types::{EventOffset, EventTable, FrameEvent, MergedEvent, NumassEvent, NumassEvents, NumassMergedEvents},
 };
 
 #[cfg(feature = "egui")]
@@ -61,16 +60,67 @@ impl From<[bool; 7]> for ChannelIgnore {
     }
 }
 
+/// Time window for merging events in ns (see [PostProcessParams]).
+///
+/// Window may depend on amplitude of the event other events are merged into
+/// (low amplitude events have slower fronts, so their parts are spread wider in time).
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum MergeWindow {
+    /// same window for all events
+    Fixed(u16),
+    /// `base + slope * amplitude`, limited to `0..=max`
+    Linear { base: u16, slope: f32, max: u16 },
+}
+
+impl MergeWindow {
+    /// Window for the event with `amplitude`.
+    pub fn get(&self, amplitude: f32) -> EventOffset {
+        match *self {
+            MergeWindow::Fixed(window) => window.into(),
+            MergeWindow::Linear { base, slope, max } => {
+                (base as f32 + slope * amplitude).clamp(0.0, max as f32) as EventOffset
+            }
+        }
+    }
+}
+
+impl Default for MergeWindow {
+    /// Window used for merging splits before it became configurable.
+    fn default() -> Self {
+        MergeWindow::Fixed(200)
+    }
+}
+
+impl std::hash::Hash for MergeWindow {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            MergeWindow::Fixed(window) => window.hash(state),
+            MergeWindow::Linear { base, slope, max } => {
+                base.hash(state);
+                slope.to_bits().hash(state);
+                max.hash(state);
+            }
+        }
+    }
+}
+
 #[repr(C)]
 /// Postprocessing params.
 #[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize, Hash)]
 pub struct PostProcessParams {
-    /// remove events inside [bad_blocks](crate::preprocess::PreprocessParams::bad_blocks) timestamps
+    /// remove events inside [bad_blocks](crate::preprocess::Preprocess::bad_blocks) timestamps
     pub cut_bad_blocks: bool,
     pub merge_frames: Option<u16>,
     pub merge_splits_first: bool,
     pub merge_close_events: bool,
     pub ignore_borders: bool,
+    /// events closer than this window to the central pixel event are merged as splits
+    #[serde(default)]
+    pub split_window: MergeWindow,
+    /// merge close events only inside this window (`None` - whole frame is merged)
+    #[serde(default)]
+    pub merge_window: Option<MergeWindow>,
 
     /// ignore channels with index in this array set to true. Default is false for all channels.
     pub ignore_channels: ChannelIgnore,
@@ -84,118 +134,148 @@ impl Default for PostProcessParams {
             merge_splits_first: false,
             merge_close_events: true,
             ignore_borders: false,
+            split_window: MergeWindow::default(),
+            merge_window: None,
             ignore_channels: ChannelIgnore::default(),
         }
     }
 }
 
-fn ignore_channels(ignore_channels: &[bool; 7], amplitudes: &mut NumassEvents) {
+pub(crate) fn ignore_channels(ignore_channels: &[bool; 7], events: &mut Vec<NumassEvent>) {
     if *ignore_channels != [false; 7] {
-        amplitudes.iter_mut().for_each(|(_, events)| {
-            events.retain(|(_, event)| match event {
-                FrameEvent::Event { channel, .. } => !ignore_channels[*channel as usize],
-                _ => true,
-            });
+        events.retain(|(_, event)| match event {
+            FrameEvent::Event { channel, .. } => !ignore_channels[*channel as usize],
+            _ => true,
         });
     }
 }
 
 /// combine proccesed events into "frames" bigger length
-fn merge_close_frames(
-    mut amplitudes: NumassEvents,
+/// (frame is merged into previous one if the distance between them is less than `merge_len`)
+///
+/// Merged frame is closed before its length exceeds [EventOffset] range, so offsets never wrap.
+pub(crate) fn merge_close_frames<I: Iterator<Item = (u64, Vec<NumassEvent>)>>(
+    frames: I,
     merge_len: u16,
-    preprocess: &Preprocess,
-) -> NumassEvents {
-    let mut amplitudes = amplitudes.iter_mut().collect::<Vec<_>>();
-
-    let mut idx = amplitudes.len() - 1;
-    let mut to_remove = Vec::new(); // collect indices to remove to avoid borrowing issues
-
-    while idx > 0 {
-        let frames_delta = amplitudes[idx].0 - amplitudes[idx - 1].0;
-        if frames_delta < merge_len as u64 {
-            let frame_offset = frames_delta - preprocess.frame_len;
-
-            let amplitudes_2 = amplitudes[idx].1.clone(); // clone to avoid borrowing issues
-            amplitudes[idx - 1].1.extend(
-                amplitudes_2
+    frame_len: u64,
+) -> impl Iterator<Item = (u64, Vec<NumassEvent>)> {
+    let mut frames = frames.peekable();
+    std::iter::from_fn(move || {
+        let (time, mut events) = frames.next()?;
+
+        let mut last_time = time;
+        let mut frame_offset = 0;
+        while let Some((next_time, next_events)) = frames.next_if(|(next_time, _)| {
+            next_time - last_time < merge_len as u64
+                && frame_offset + (next_time - last_time) + frame_len <= EventOffset::MAX as u64
+        }) {
+            frame_offset += next_time - last_time - frame_len;
+            events.extend(
+                next_events
                     .into_iter()
-                    .map(|(offset, event)| (offset + frame_offset as u16, event)),
+                    .map(|(offset, event)| (offset + frame_offset as EventOffset, event)),
             );
-            
-            to_remove.push(idx);
+            last_time = next_time;
         }
-        idx -= 1;
-    }
 
-    amplitudes.iter().enumerate().filter_map(|(idx, (offset, frame))| {
-        if to_remove.contains(&idx) {
-            None
-        } else {
-            Some((**offset, frame.to_vec()))
-        }
-    }).collect::<NumassEvents>()
+        Some((time, events))
+    })
 }
 
-/// Built-in postprocessing algorithm.
-/// > [!NOTE]  
-/// > For now cut_bad_blocks works only with `merge_close_events` set to true.
-/// > TODO: fix it.
+/// Built-in postprocessing algorithm (frame by frame).
+///
+/// Frames must be sorted by time. Use this function to postprocess events
+/// without collecting them first, otherwise use [post_process] or [post_process_table].
+///
+/// Stages are applied in the following order, each one only if its own flag is set:
+/// 1. merge frames ([merge_frames](PostProcessParams::merge_frames))
+/// 2. cut bad blocks ([cut_bad_blocks](PostProcessParams::cut_bad_blocks))
+/// 3. merge splits ([merge_splits_first](PostProcessParams::merge_splits_first))
+/// 4. merge close events ([merge_close_events](PostProcessParams::merge_close_events))
+/// 5. ignore channels ([ignore_channels](PostProcessParams::ignore_channels))
+///
+/// Params are converted into [pipeline](crate::pipeline) with [PostProcessParams::to_stages].
+pub fn post_process_frames<'a, I: Iterator<Item = (u64, Vec<NumassEvent>)> + 'a>(
+    frames: I,
+    preprocess: &'a Preprocess,
+    params: &'a PostProcessParams,
+) -> Box<dyn Iterator<Item = (u64, Vec<NumassEvent>)> + 'a> {
+    run_pipeline(frames, preprocess, params.to_stages(), &CustomStages::new())
+        .expect("params preset has no custom stages")
+}
+
+/// Built-in postprocessing algorithm (see [post_process_frames] for stages order).
 pub fn post_process(
     process_result: (NumassEvents, Preprocess),
     params: &PostProcessParams,
 ) -> (NumassEvents, Preprocess) {
-    let (mut amplitudes, preprocess_params) = process_result;
-
-    if let Some(frame_len) = params.merge_frames {
-        amplitudes = merge_close_frames(amplitudes, frame_len, &preprocess_params);
-    }
+    let (amplitudes, preprocess) = process_result;
+    let amplitudes = post_process_frames(amplitudes.into_iter(), &preprocess, params)
+        .collect::<NumassEvents>();
+    (amplitudes, preprocess)
+}
 
-    if !params.merge_close_events {
-        let mut amplitudes = amplitudes;
-        ignore_channels(&params.ignore_channels.into(), &mut amplitudes);
-        return (amplitudes, preprocess_params);
-    }
+/// Built-in postprocessing algorithm for [EventTable] (see [post_process]).
+///
+/// Table is not processed in place: frames are converted into `Vec<NumassEvent>` one by one
+/// (one allocation per frame) and collected into a new table.
+pub fn post_process_table(
+    process_result: (EventTable, Preprocess),
+    params: &PostProcessParams,
+) -> (EventTable, Preprocess) {
+    let (table, preprocess) = process_result;
+    let table = post_process_frames(table.into_frames(), &preprocess, params)
+        .collect::<EventTable>();
+    (table, preprocess)
+}
 
-    // TODO: think about code deduplication
-    let mut amplitudes = if params.cut_bad_blocks {
-        amplitudes
-            .into_iter()
-            .filter(|(timestamp, _)| {
-                let curr_block = (timestamp / CUTOFF_BIN_SIZE) as usize;
-                !preprocess_params.bad_blocks.contains(&curr_block)
-            })
-            .map(|(time, events)| {
-                let events_postprocessed = post_process_frame(
-                    events,
-                    params,
-                    #[cfg(feature = "egui")]
-                    None,
-                );
-                (time, events_postprocessed)
-            })
-            .collect::<BTreeMap<_, _>>()
-    } else {
-        amplitudes
-            .into_iter()
-            .map(|(time, events)| {
-                let events_postprocessed = post_process_frame(
-                    events,
-                    params,
-                    #[cfg(feature = "egui")]
-                    None,
-                );
-                (time, events_postprocessed)
-            })
-            .collect::<BTreeMap<_, _>>()
+/// Same as [post_process_frames] but merged events keep their contributors (see [MergedEvent]).
+///
+/// Summed events are the same as in [post_process_frames] output.
+pub fn post_process_frames_merged<'a, I: Iterator<Item = (u64, Vec<NumassEvent>)> + 'a>(
+    frames: I,
+    preprocess: &'a Preprocess,
+    params: &'a PostProcessParams,
+) -> impl Iterator<Item = (u64, Vec<MergedEvent>)> + 'a {
+    // frame-level stages only, per-frame stages are tracked below
+    let frames_params = PostProcessParams {
+        merge_splits_first: false,
+        merge_close_events: false,
+        ignore_channels: ChannelIgnore::default(),
+        ..*params
     };
+    let ignored: [bool; 7] = params.ignore_channels.into();
+
+    let frames = run_pipeline(frames, preprocess, frames_params.to_stages(), &CustomStages::new())
+        .expect("params preset has no custom stages");
+    frames.map(move |(time, events)| {
+        let mut events = post_process_frame_tracked(
+            events.into_iter().map(MergedEvent::from).collect(),
+            params,
+            #[cfg(feature = "egui")]
+            None,
+        );
+        events.retain(|event| match event.event {
+            FrameEvent::Event { channel, .. } => !ignored[channel as usize],
+            _ => true,
+        });
+        (time, events)
+    })
+}
 
-    ignore_channels(&params.ignore_channels.into(), &mut amplitudes);
-
-    (amplitudes, preprocess_params)
+/// Built-in postprocessing algorithm with merged events provenance (see [post_process_frames_merged]).
+pub fn post_process_merged(
+    process_result: (NumassEvents, Preprocess),
+    params: &PostProcessParams,
+) -> (NumassMergedEvents, Preprocess) {
+    let (amplitudes, preprocess) = process_result;
+    let amplitudes = post_process_frames_merged(amplitudes.into_iter(), &preprocess, params)
+        .collect::<NumassMergedEvents>();
+    (amplitudes, preprocess)
 }
 
-fn is_neighbour(ch_1: u8, ch_2: u8) -> bool {
+/// Pixels share a border (same pixel and centre pixel are neighbours of any pixel).
+pub(crate) fn is_neighbour(ch_1: u8, ch_2: u8) -> bool {
     if ch_1 == ch_2 {
         return true;
     }
@@ -213,10 +293,28 @@ fn is_neighbour(ch_1: u8, ch_2: u8) -> bool {
     DETECTOR_BORDERS.contains(&border)
 }
 
-fn merge_splits(
-    mut events: Vec<NumassEvent>,
+pub(crate) fn merge_splits(
+    events: Vec<NumassEvent>,
+    window: MergeWindow,
     #[cfg(feature = "egui")] ui: &mut Option<&mut PlotUi>,
 ) -> Vec<NumassEvent> {
+    merge_splits_tracked(
+        events.into_iter().map(MergedEvent::from).collect(),
+        window,
+        #[cfg(feature = "egui")]
+        ui,
+    )
+    .into_iter()
+    .map(NumassEvent::from)
+    .collect()
+}
+
+/// Same as [merge_splits] but keeps merged events provenance.
+fn merge_splits_tracked(
+    mut events: Vec<MergedEvent>,
+    window: MergeWindow,
+    #[cfg(feature = "egui")] ui: &mut Option<&mut PlotUi>,
+) -> Vec<MergedEvent> {
     #[cfg(feature = "egui")]
     let mut merges = vec![];
 
@@ -229,69 +327,71 @@ fn merge_splits(
             continue;
         }
 
-        if let (
-            offset,
-            FrameEvent::Event {
-                channel,
-                mut amplitude,
-                size: _,
-            },
-        ) = events[idx]
+        if let FrameEvent::Event {
+            channel, amplitude, ..
+        } = events[idx].event
         {
+            let offset = events[idx].offset;
+            let window = window.get(amplitude);
+            let mut merged = events[idx].clone();
+
             if channel == 5 {
-                let mut idx_past = (idx - 1) as isize;
-                while idx_past >= 0 && events[idx_past as usize].0.abs_diff(offset) < 200 {
-                    if let (
-                        _offset_past,
-                        FrameEvent::Event {
-                            #[cfg(feature = "egui")]
-                                channel: channel_past,
+                let mut idx_past = idx as isize - 1;
+                while idx_past >= 0 && events[idx_past as usize].offset.abs_diff(offset) < window {
+                    let past = &events[idx_past as usize];
+                    // event can be already absorbed by the previous channel 5 event
+                    if to_remove.contains(&(idx_past as usize)) {
+                        idx_past -= 1;
+                        continue;
+                    }
+                    if let FrameEvent::Event {
+                        #[cfg(feature = "egui")]
+                            channel: channel_past,
+                        #[cfg(feature = "egui")]
                             amplitude: amplitude_past,
-                            ..
-                        },
-                    ) = events[idx_past as usize]
+                        ..
+                    } = past.event
                     {
-                        amplitude += amplitude_past;
+                        merged.absorb(past);
                         to_remove.push(idx_past as usize);
 
                         #[cfg(feature = "egui")]
-                        merges.push((idx, (channel_past, _offset_past, amplitude_past)));
+                        merges.push((idx, (channel_past, past.offset, amplitude_past)));
                     }
                     idx_past -= 1;
                 }
 
                 if idx != events.len() - 1 {
                     let mut idx_next = idx + 1;
-                    while idx_next < events.len() && events[idx_next].0.abs_diff(offset) < 200 {
-                        if let (
-                            _offset_next,
-                            FrameEvent::Event {
-                                #[cfg(feature = "egui")]
-                                    channel: channel_next,
+                    while idx_next < events.len() && events[idx_next].offset.abs_diff(offset) < window {
+                        let next = &events[idx_next];
+                        if to_remove.contains(&idx_next) {
+                            idx_next += 1;
+                            continue;
+                        }
+                        if let FrameEvent::Event {
+                            #[cfg(feature = "egui")]
+                                channel: channel_next,
+                            #[cfg(feature = "egui")]
                                 amplitude: amplitude_next,
-                                ..
-                            },
-                        ) = events[idx_next]
+                            ..
+                        } = next.event
                         {
-                            amplitude += amplitude_next;
+                            merged.absorb(next);
                             to_remove.push(idx_next);
 
                             #[cfg(feature = "egui")]
-                            merges.push((idx, (channel_next, _offset_next, amplitude_next)));
+                            merges.push((idx, (channel_next, next.offset, amplitude_next)));
                         }
                         idx_next += 1;
                     }
                 }
             }
 
-            events[idx] = (
-                offset,
-                FrameEvent::Event {
-                    channel,
-                    amplitude,
-                    size: 0,
-                },
-            );
+            if let FrameEvent::Event { size, .. } = &mut merged.event {
+                *size = 0;
+            }
+            events[idx] = merged;
         }
 
         idx += 1;
@@ -300,7 +400,8 @@ fn merge_splits(
     #[cfg(feature = "egui")]
     if let Some(ui) = ui {
         for (idx, (channel2, pos2, amplitude2)) in merges {
-            if let (pos1, FrameEvent::Event { amplitude, .. }) = events[idx] {
+            if let FrameEvent::Event { amplitude, .. } = events[idx].event {
+                let pos1 = events[idx].offset;
                 ui.line(
                     Line::new("".to_owned(), vec![ // TODO: check first arg
                         [pos1 as f64 / 8.0, amplitude as f64],
@@ -314,6 +415,7 @@ fn merge_splits(
     }
 
     to_remove.sort();
+    to_remove.dedup();
     to_remove.iter().rev().for_each(|&idx| {
         if idx < events.len() {
             // TODO: find solution
@@ -324,66 +426,112 @@ fn merge_splits(
     events
 }
 
+/// Per-frame postprocessing stages (merge splits and merge close events, see [post_process_frames]).
 pub fn post_process_frame(
-    mut events: Vec<NumassEvent>,
+    events: Vec<NumassEvent>,
     params: &PostProcessParams,
-    #[cfg(feature = "egui")] mut ui: Option<&mut PlotUi>,
+    #[cfg(feature = "egui")] ui: Option<&mut PlotUi>,
 ) -> Vec<NumassEvent> {
-    if !params.merge_close_events {
-        return events;
-    }
+    post_process_frame_tracked(
+        events.into_iter().map(MergedEvent::from).collect(),
+        params,
+        #[cfg(feature = "egui")]
+        ui,
+    )
+    .into_iter()
+    .map(NumassEvent::from)
+    .collect()
+}
 
+/// Same as [post_process_frame] but keeps merged events provenance (see [MergedEvent]).
+pub fn post_process_frame_tracked(
+    mut events: Vec<MergedEvent>,
+    params: &PostProcessParams,
+    #[cfg(feature = "egui")] mut ui: Option<&mut PlotUi>,
+) -> Vec<MergedEvent> {
     if params.merge_splits_first {
-        events = merge_splits(
+        events = merge_splits_tracked(
             events,
+            params.split_window,
             #[cfg(feature = "egui")]
             &mut ui,
         );
     }
 
+    if params.merge_close_events {
+        events = merge_close_events_tracked(
+            events,
+            params.ignore_borders,
+            params.merge_window,
+            #[cfg(feature = "egui")]
+            ui,
+        );
+    }
+
+    events
+}
+
+/// Merge neighbouring events of the frame into one (see `merge close events` widget hint for the scheme).
+pub(crate) fn merge_close_events(
+    events: Vec<NumassEvent>,
+    ignore_borders: bool,
+    window: Option<MergeWindow>,
+    #[cfg(feature = "egui")] ui: Option<&mut PlotUi>,
+) -> Vec<NumassEvent> {
+    merge_close_events_tracked(
+        events.into_iter().map(MergedEvent::from).collect(),
+        ignore_borders,
+        window,
+        #[cfg(feature = "egui")]
+        ui,
+    )
+    .into_iter()
+    .map(NumassEvent::from)
+    .collect()
+}
+
+/// Same as [merge_close_events] but keeps merged events provenance.
+fn merge_close_events_tracked(
+    mut events: Vec<MergedEvent>,
+    ignore_borders: bool,
+    window: Option<MergeWindow>,
+    #[cfg(feature = "egui")] ui: Option<&mut PlotUi>,
+) -> Vec<MergedEvent> {
     #[cfg(feature = "egui")]
     let mut merges = vec![];
 
     let mut idx = 0;
     while idx < events.len() {
-        if let (
-            offset,
-            FrameEvent::Event {
-                channel,
-                mut amplitude,
-                size,
-            },
-        ) = events[idx]
+        if let FrameEvent::Event {
+            channel, amplitude, ..
+        } = events[idx].event
         {
+            let offset = events[idx].offset;
+            let window = window.map(|window| window.get(amplitude));
+            let mut merged = events[idx].clone();
+
             let mut idx_next = events.len() - 1;
             while idx_next > idx {
-                if let (
-                    _pos2,
-                    FrameEvent::Event {
-                        channel: channel_next,
+                if let FrameEvent::Event {
+                    channel: channel_next,
+                    #[cfg(feature = "egui")]
                         amplitude: amplitude_next,
-                        ..
-                    },
-                ) = events[idx_next]
+                    ..
+                } = events[idx_next].event
                 {
-                    if params.ignore_borders || is_neighbour(channel, channel_next) {
+                    let in_window = window
+                        .is_none_or(|window| events[idx_next].offset.abs_diff(offset) < window);
+                    if in_window && (ignore_borders || is_neighbour(channel, channel_next)) {
                         #[cfg(feature = "egui")]
-                        merges.push((idx, (channel_next, _pos2, amplitude_next)));
+                        merges.push((idx, (channel_next, events[idx_next].offset, amplitude_next)));
 
-                        amplitude += amplitude_next;
-                        events.remove(idx_next);
+                        let next = events.remove(idx_next);
+                        merged.absorb(&next);
                     }
                 }
                 idx_next -= 1;
             }
-            events[idx] = (
-                offset,
-                FrameEvent::Event {
-                    channel,
-                    amplitude,
-                    size,
-                },
-            );
+            events[idx] = merged;
         }
         idx += 1;
     }
@@ -393,13 +541,11 @@ pub fn post_process_frame(
         // draw merged points first
         let merged_idxs = merges.iter().map(|(idx, _)| *idx).collect::<HashSet<_>>();
         for idx in merged_idxs {
-            if let (
-                pos,
-                FrameEvent::Event {
-                    channel, amplitude, ..
-                },
-            ) = events[idx]
+            if let FrameEvent::Event {
+                channel, amplitude, ..
+            } = events[idx].event
             {
+                let pos = events[idx].offset;
                 let name = format!("ch# {channel} merged");
                 ui.points(
                     Points::new(name, vec![[pos as f64 / 8.0, amplitude as f64]])
@@ -412,7 +558,8 @@ pub fn post_process_frame(
         }
 
         for (idx, (channel2, pos2, amplitude2)) in merges {
-            if let (pos1, FrameEvent::Event { amplitude, .. }) = events[idx] {
+            if let FrameEvent::Event { amplitude, .. } = events[idx].event {
+                let pos1 = events[idx].offset;
                 ui.line(
                     Line::new(String::new(), vec![ // TODO: add name
                         [pos1 as f64 / 8.0, amplitude as f64],
@@ -427,3 +574,250 @@ pub fn post_process_frame(
 
     events
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use super::*;
+    use crate::{preprocess::QualityMetrics, types::EventPart};
+
+    const BLOCK: u64 = 1_000_000;
+    const BAD_FRAME: u64 = BLOCK + 200_000;
+
+    fn preprocess() -> Preprocess {
+        Preprocess {
+            baseline: None,
+            hv: 0.0,
+            start_time: Default::default(),
+            acquisition_time: 3 * BLOCK,
+            frame_len: 1_000,
+            bad_blocks: BTreeSet::from([1]),
+            cutoff_bin_size: BLOCK,
+            bad_block_records: BTreeMap::new(),
+            quality: QualityMetrics::default(),
+            block_rates: vec![],
+        }
+    }
+
+    fn event(offset: EventOffset, channel: u8, amplitude: f32) -> NumassEvent {
+        (
+            offset,
+            FrameEvent::Event {
+                channel,
+                amplitude,
+                size: 10,
+            },
+        )
+    }
+
+    /// Every stage changes the fixture: frames 0 and 1500 are merged by `merge_frames`,
+    /// frame [BAD_FRAME] is inside the bad block, frame 0 has a split of the central pixel (5)
+    /// and neighbour events, channel 2 is ignored (the last frame has single channel 2 event).
+    fn frames() -> NumassEvents {
+        NumassEvents::from([
+            (
+                0,
+                vec![event(100, 5, 10.0), event(150, 0, 5.0), event(600, 1, 3.0)],
+            ),
+            (1_500, vec![event(50, 2, 4.0)]),
+            (BAD_FRAME, vec![event(10, 1, 7.0)]),
+            (2 * BLOCK, vec![event(20, 6, 4.0), event(40, 2, 2.0)]),
+            (2 * BLOCK + 5_000, vec![event(30, 2, 6.0)]),
+        ])
+    }
+
+    fn params(flags: u8) -> PostProcessParams {
+        PostProcessParams {
+            merge_frames: (flags & 1 != 0).then_some(2_000),
+            cut_bad_blocks: flags & 2 != 0,
+            merge_splits_first: flags & 4 != 0,
+            merge_close_events: flags & 8 != 0,
+            ignore_channels: if flags & 16 != 0 {
+                [false, false, true, false, false, false, false].into()
+            } else {
+                ChannelIgnore::default()
+            },
+            ..PostProcessParams::default()
+        }
+    }
+
+    /// Stages applied one by one in the documented order.
+    fn reference(params: &PostProcessParams) -> NumassEvents {
+        let preprocess = preprocess();
+        let mut frames = frames().into_iter().collect::<Vec<_>>();
+        if let Some(merge_len) = params.merge_frames {
+            frames =
+                merge_close_frames(frames.into_iter(), merge_len, preprocess.frame_len).collect();
+        }
+        if params.cut_bad_blocks {
+            frames.retain(|(time, _)| {
+                !preprocess
+                    .bad_blocks
+                    .contains(&((time / preprocess.cutoff_bin_size) as usize))
+            });
+        }
+        frames
+            .into_iter()
+            .map(|(time, mut events)| {
+                if params.merge_splits_first {
+                    events = merge_splits(
+                        events,
+                        params.split_window,
+                        #[cfg(feature = "egui")]
+                        &mut None,
+                    );
+                }
+                if params.merge_close_events {
+                    events = merge_close_events(
+                        events,
+                        params.ignore_borders,
+                        params.merge_window,
+                        #[cfg(feature = "egui")]
+                        None,
+                    );
+                }
+                ignore_channels(&params.ignore_channels.into(), &mut events);
+                (time, events)
+            })
+            .collect()
+    }
+
+    /// [FrameEvent] is not comparable, events are compared as columns.
+    fn table(events: &NumassEvents) -> EventTable {
+        events.clone().into_iter().collect()
+    }
+
+    fn channels(events: &NumassEvents) -> BTreeSet<u8> {
+        events
+            .values()
+            .flatten()
+            .filter_map(|(_, event)| match event {
+                FrameEvent::Event { channel, .. } => Some(*channel),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flag_combinations() {
+        for flags in 0..32 {
+            let params = params(flags);
+            let (events, _) = post_process((frames(), preprocess()), &params);
+            let expected = reference(&params);
+            assert_eq!(table(&events), table(&expected), "{params:?}");
+
+            let (merged, _) = post_process_merged((frames(), preprocess()), &params);
+            let merged = merged
+                .into_iter()
+                .map(|(time, events)| (time, events.into_iter().map(NumassEvent::from).collect()))
+                .collect::<NumassEvents>();
+            assert_eq!(table(&merged), table(&expected), "{params:?}");
+
+            assert_eq!(events.contains_key(&1_500), params.merge_frames.is_none());
+            assert_eq!(events.contains_key(&BAD_FRAME), !params.cut_bad_blocks);
+            assert_eq!(
+                channels(&events).contains(&2),
+                params.ignore_channels == ChannelIgnore::default()
+            );
+            let unmerged_params = PostProcessParams {
+                merge_splits_first: false,
+                merge_close_events: false,
+                ..params
+            };
+            let (unmerged, _) = post_process((frames(), preprocess()), &unmerged_params);
+            assert_eq!(
+                events[&0].len() < unmerged[&0].len(),
+                params.merge_splits_first || params.merge_close_events
+            );
+        }
+    }
+
+    #[test]
+    fn cut_bad_blocks_without_merge_close_events() {
+        let params = PostProcessParams {
+            cut_bad_blocks: true,
+            merge_close_events: false,
+            ..PostProcessParams::default()
+        };
+        let (events, _) = post_process((frames(), preprocess()), &params);
+        assert!(!events.contains_key(&BAD_FRAME));
+        assert_eq!(events.len(), frames().len() - 1);
+        assert_eq!(events[&0].len(), 3);
+    }
+
+    #[test]
+    fn merge_frames_chain_beyond_u16() {
+        // every next frame starts 4 µs later, so merged offsets grow by 3 µs (frame_len is cut)
+        let frames = (0..30u64).map(|idx| (idx * 4_000, vec![event(500, 0, idx as f32)]));
+        let merged = merge_close_frames(frames, 5_000, 1_000).collect::<Vec<_>>();
+        assert_eq!(merged.len(), 1);
+        let offsets = merged[0]
+            .1
+            .iter()
+            .map(|(offset, _)| *offset)
+            .collect::<Vec<_>>();
+        let expected = (0..30).map(|idx| 500 + idx * 3_000).collect::<Vec<_>>();
+        assert_eq!(offsets, expected);
+        assert!(offsets[29] > u16::MAX as EventOffset);
+    }
+
+    #[test]
+    fn merge_frames_stops_before_offset_overflow() {
+        const STEP: u64 = 65_000;
+        const FRAME_LEN: u64 = 1_000;
+        let frames = (0..70_000u64).map(|idx| (idx * STEP, vec![event(999, 0, 1.0)]));
+        let merged = merge_close_frames(frames, u16::MAX, FRAME_LEN).collect::<Vec<_>>();
+
+        // frame is merged while its end fits into the offset
+        let chain = (EventOffset::MAX as u64 - STEP - FRAME_LEN) / (STEP - FRAME_LEN) + 2;
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].1.len() as u64, chain);
+        assert_eq!(merged[1].0, chain * STEP);
+        assert_eq!(merged[1].1.len() as u64, 70_000 - chain);
+        assert!(merged[0]
+            .1
+            .windows(2)
+            .all(|pair| pair[1].0 - pair[0].0 == (STEP - FRAME_LEN) as EventOffset));
+    }
+
+    #[test]
+    fn merge_splits_shared_neighbour() {
+        // event 60 is inside the window of both channel 5 events, it is absorbed only once
+        let events = vec![
+            event(0, 5, 10.0),
+            event(60, 0, 2.0),
+            event(120, 5, 7.0),
+            event(300, 1, 4.0),
+        ];
+        let merged = merge_splits_tracked(
+            events.into_iter().map(MergedEvent::from).collect(),
+            MergeWindow::Fixed(100),
+            #[cfg(feature = "egui")]
+            &mut None,
+        );
+
+        let survived = merged
+            .iter()
+            .map(|event| {
+                let FrameEvent::Event {
+                    channel, amplitude, ..
+                } = event.event
+                else {
+                    panic!("unexpected {event:?}")
+                };
+                (event.offset, channel, amplitude)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(survived, vec![(0, 5, 12.0), (120, 5, 7.0), (300, 1, 4.0)]);
+
+        let part = |offset, channel, amplitude| EventPart {
+            offset,
+            channel,
+            amplitude,
+        };
+        assert_eq!(merged[0].parts, vec![part(0, 5, 10.0), part(60, 0, 2.0)]);
+        assert!(merged[1].parts.is_empty());
+        assert!(merged[2].parts.is_empty());
+    }
+}
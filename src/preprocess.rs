@@ -2,7 +2,10 @@
 //! This module contains preprocessing calclulations per point.
 //!
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+};
 
 use chrono::NaiveDateTime;
 use numass::{
@@ -13,20 +16,111 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     histogram::PointHistogram,
-    process::Algorithm,
-    types::{NumassWaveforms, NumassWaveformsFast},
+    process::{detect_overflow, detect_reset, Algorithm},
+    types::{NumassFrameFast, NumassWaveforms, NumassWaveformsCow, NumassWaveformsFast},
     utils::correct_frame_time,
 };
 
 /// Размер блока, который будет вырезан, если в нем обнаружены проблемы (в нс)
+/// (значение по умолчанию для [BadBlockParams::cutoff_bin_size])
 pub const CUTOFF_BIN_SIZE: u64 = 1_000_000_000;
 
 /// Размер блока, который будет проверяться на наличие проблем (в нс)
+/// (значение по умолчанию для [BadBlockParams::check_bin_size])
 pub const CHECK_BIN_SIZE: u64 = 10_000_000;
 
 /// Порог по HV для проверки точки (точки с HV выше не будут проверяться)
+/// (значение по умолчанию для [BadBlockParams::hv_threshold])
 pub const CHECK_HV_THRESHOLD: f32 = 16e3;
 
+/// Bad blocks detection params (part of [ProcessParams](crate::process::ProcessParams)).
+///
+/// Point is splitted into `cutoff_bin_size` blocks, each block is splitted into `check_bin_size` sub-blocks.
+/// Block is marked as bad if any of enabled criteria fires.
+///
+/// Params are checked with [validate](BadBlockParams::validate) on deserialization
+/// and before processing (see [Preprocess::from_waveforms]).
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "BadBlockParamsUnchecked")]
+pub struct BadBlockParams {
+    /// size of the block that is cut (in ns)
+    pub cutoff_bin_size: u64,
+    /// size of the checked sub-block (in ns), must not exceed `cutoff_bin_size`
+    pub check_bin_size: u64,
+    /// points with HV above threshold are not checked (in V)
+    pub hv_threshold: u32,
+    /// block is bad if any of its sub-blocks has no triggers
+    pub empty_bins: bool,
+    /// block is bad if any of its sub-blocks has trigger count above expected by N sigma
+    /// (rate spikes, e.g. HV discharges)
+    pub rate_spike_sigma: Option<u32>,
+    /// block is bad if it contains more hardware resets than this
+    /// (resets are detected only for algorithms with [HWResetParams](crate::process::HWResetParams))
+    pub max_resets: Option<u32>,
+    /// block is bad if it contains more overflowed waveforms than this
+    pub max_overflows: Option<u32>,
+}
+
+impl Default for BadBlockParams {
+    fn default() -> Self {
+        Self {
+            cutoff_bin_size: CUTOFF_BIN_SIZE,
+            check_bin_size: CHECK_BIN_SIZE,
+            hv_threshold: CHECK_HV_THRESHOLD as u32,
+            empty_bins: true,
+            rate_spike_sigma: None,
+            max_resets: None,
+            max_overflows: None,
+        }
+    }
+}
+
+impl BadBlockParams {
+    /// Check that block sizes are positive and sub-block is not larger than block.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.check_bin_size == 0 || self.cutoff_bin_size == 0 {
+            return Err("block sizes must be positive".to_owned());
+        }
+        if self.check_bin_size > self.cutoff_bin_size {
+            return Err(format!(
+                "sub-block size {} is larger than block size {}",
+                self.check_bin_size, self.cutoff_bin_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Unchecked [BadBlockParams] (deserialization helper).
+#[derive(Deserialize)]
+struct BadBlockParamsUnchecked {
+    cutoff_bin_size: u64,
+    check_bin_size: u64,
+    hv_threshold: u32,
+    empty_bins: bool,
+    rate_spike_sigma: Option<u32>,
+    max_resets: Option<u32>,
+    max_overflows: Option<u32>,
+}
+
+impl TryFrom<BadBlockParamsUnchecked> for BadBlockParams {
+    type Error = String;
+
+    fn try_from(params: BadBlockParamsUnchecked) -> Result<Self, Self::Error> {
+        let params = BadBlockParams {
+            cutoff_bin_size: params.cutoff_bin_size,
+            check_bin_size: params.check_bin_size,
+            hv_threshold: params.hv_threshold,
+            empty_bins: params.empty_bins,
+            rate_spike_sigma: params.rate_spike_sigma,
+            max_resets: params.max_resets,
+            max_overflows: params.max_overflows,
+        };
+        params.validate()?;
+        Ok(params)
+    }
+}
+
 /// Неизменяемые параметры, необходимые для обработки кадра
 /// могут либо задаваться статично, либо на каждую точку
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -46,8 +140,30 @@ pub struct Preprocess {
     pub frame_len: u64,
 
     /// номера блоков, которые нужно исключить из анализа
-    /// размер блока равен [CUTOFF_BIN_SIZE](crate::preprocess::CUTOFF_BIN_SIZE)
+    /// размер блока равен [cutoff_bin_size](Preprocess::cutoff_bin_size)
     pub bad_blocks: BTreeSet<usize>,
+
+    /// размер блока в наносекундах (см. [BadBlockParams::cutoff_bin_size])
+    #[serde(default = "default_cutoff_bin_size")]
+    pub cutoff_bin_size: u64,
+
+    /// подробности по каждому блоку из [bad_blocks](Preprocess::bad_blocks)
+    /// (причины исключения и статистика блока)
+    #[serde(default)]
+    pub bad_block_records: BTreeMap<usize, BadBlockRecord>,
+
+    /// метрики качества данных для всей точки
+    #[serde(default)]
+    pub quality: QualityMetrics,
+
+    /// скорость счета триггеров (в Гц) по блокам размера [cutoff_bin_size](Preprocess::cutoff_bin_size)
+    /// (считается для всех точек, независимо от HV)
+    #[serde(default)]
+    pub block_rates: Vec<f32>,
+}
+
+fn default_cutoff_bin_size() -> u64 {
+    CUTOFF_BIN_SIZE
 }
 
 impl Preprocess {
@@ -56,7 +172,25 @@ impl Preprocess {
         meta: Option<NumassMeta>,
         point: &rsb_event::Point,
         algo: &Algorithm,
-    ) -> Self {
+        bad_block_params: &BadBlockParams,
+    ) -> Result<Self, String> {
+        Self::from_waveforms(meta, &extract_waveforms(point)?, algo, bad_block_params)
+    }
+
+    /// Same as [from_point](Preprocess::from_point) but for already extracted waveforms
+    /// (e.g. from [MappedPoint](crate::mmap::MappedPoint)).
+    ///
+    /// Fails if `bad_block_params` are invalid (see [BadBlockParams::validate])
+    /// or `meta` is not a point metadata.
+    pub fn from_waveforms(
+        meta: Option<NumassMeta>,
+        waveforms: &NumassWaveformsCow,
+        algo: &Algorithm,
+        bad_block_params: &BadBlockParams,
+    ) -> Result<Self, String> {
+        bad_block_params
+            .validate()
+            .map_err(|err| format!("invalid bad block params: {err}"))?;
 
         let (acquisition_time, start_time) =
             if let Some(NumassMeta::Reply(Reply::AcquirePoint {
@@ -67,7 +201,7 @@ impl Preprocess {
             {
                 ((acquisition_time * 1e9) as u64, start_time)
             } else {
-                panic!("acquisition_time/start_time not found in metadata")
+                return Err("acquisition_time/start_time not found in metadata".to_owned());
             };
 
         let hv =
@@ -85,44 +219,31 @@ impl Preprocess {
                 -1.0 // TODO: fix this!
             };
 
-        let bad_blocks = if hv > CHECK_HV_THRESHOLD {
-            BTreeSet::new()
+        let stats = BlockStats::collect(waveforms, acquisition_time, algo, bad_block_params);
+        let block_rates = stats.block_rates(bad_block_params);
+        let bad_block_records = if hv > bad_block_params.hv_threshold as f32 {
+            BTreeMap::new()
         } else {
-            let mut trigger_density_local =
-                PointHistogram::new_step(0.0..(acquisition_time as f32), CHECK_BIN_SIZE as f32);
-
-            for channel in &point.channels {
-                for block in &channel.blocks {
-                    for frame in &block.frames {
-                        trigger_density_local.add(0, correct_frame_time(frame.time) as f32);
-                    }
-                }
-            }
-
-            let mut bad_blocks = BTreeSet::new();
-
-            trigger_density_local.channels[&0]
-                .iter()
-                .enumerate()
-                .for_each(|(idx, count)| {
-                    if ((idx + 1) as u64 * CHECK_BIN_SIZE) <= acquisition_time && *count == 0.0 {
-                        let block_idx = (idx as u64 * CHECK_BIN_SIZE) / CUTOFF_BIN_SIZE;
-                        bad_blocks.insert(block_idx as usize);
-                    }
-                });
-
-            bad_blocks
+            stats.bad_block_records(&block_rates, bad_block_params)
+        };
+        let bad_blocks = bad_block_records.keys().copied().collect::<BTreeSet<_>>();
+
+        let cut_time = cut_time(&bad_blocks, bad_block_params.cutoff_bin_size, acquisition_time);
+        let quality = QualityMetrics {
+            trigger_rate: stats.total_triggers as f32 / acquisition_time as f32 * 1e9,
+            resets: stats.resets.values().sum(),
+            overflows: stats.overflows.values().sum(),
+            cut_fraction: cut_time as f32 / acquisition_time as f32,
         };
 
-        let frame_len = ((point
-            .channels.first().unwrap()
-            .blocks.first().unwrap()
-            .frames.first().unwrap()
-            .data.len() / 2) * 8) as u64;
+        let frame_len = (waveforms
+            .values().next().unwrap()
+            .values().next().unwrap()
+            .len() * 8) as u64;
 
         let baseline = match &algo {
             Algorithm::Trapezoid { .. } => {
-                Some(baseline_from_point(point, algo))
+                Some(baseline_from_waveforms(waveforms, algo))
             }
             Algorithm::Max => None,
             Algorithm::FirstPeak { .. } => None,
@@ -130,21 +251,273 @@ impl Preprocess {
             Algorithm::LongDiff { .. } => None,
         };
 
-        Self {
+        Ok(Self {
             baseline,
             acquisition_time,
             start_time,
             frame_len,
             hv,
             bad_blocks,
-        }
+            cutoff_bin_size: bad_block_params.cutoff_bin_size,
+            bad_block_records,
+            quality,
+            block_rates,
+        })
     }
     /// calculate effective time of acquisition after removing bad blocks (in nanoseconds)
-    /// `acquisition_time - bad_blocks_count as u64 * CUTOFF_BIN_SIZE`
+    /// `acquisition_time - bad_blocks_count as u64 * cutoff_bin_size`
+    /// (the last block is clipped to the acquisition time)
     pub fn effective_time(&self) -> u64 {
-        let bad_blocks_count = self.bad_blocks.len();
-        self.acquisition_time - bad_blocks_count as u64 * CUTOFF_BIN_SIZE
+        self.acquisition_time
+            .saturating_sub(cut_time(&self.bad_blocks, self.cutoff_bin_size, self.acquisition_time))
     }
+
+    /// [block_rates](Preprocess::block_rates) as (block start in seconds, rate in Hz) for plotting.
+    pub fn block_rates_points(&self) -> Vec<[f64; 2]> {
+        self.block_rates
+            .iter()
+            .enumerate()
+            .map(|(idx, rate)| [(idx as u64 * self.cutoff_bin_size) as f64 * 1e-9, *rate as f64])
+            .collect()
+    }
+}
+
+/// Reason why block was marked as bad (see [BadBlockParams] criteria).
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BadBlockReason {
+    /// sub-block without triggers
+    EmptyBins,
+    /// significant trigger rate excess (e.g. HV discharge)
+    RateSpike,
+    ResetStorm,
+    OverflowBurst,
+}
+
+/// Details of the bad block (see [Preprocess::bad_block_records]).
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct BadBlockRecord {
+    /// criteria that fired for this block
+    pub reasons: BTreeSet<BadBlockReason>,
+    /// trigger counts of the block sub-blocks (see [BadBlockParams::check_bin_size])
+    pub sub_bin_counts: Vec<usize>,
+    /// trigger rate in the block (in Hz)
+    pub rate: f32,
+    /// number of frames with hardware reset in the block
+    pub resets: u32,
+    /// number of overflowed waveforms in the block
+    pub overflows: u32,
+}
+
+/// Point-level data quality metrics (see [Preprocess::quality]).
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct QualityMetrics {
+    /// mean trigger rate (in Hz)
+    pub trigger_rate: f32,
+    /// number of frames with hardware reset
+    /// (resets are detected only for algorithms with [HWResetParams](crate::process::HWResetParams))
+    pub resets: u32,
+    /// number of overflowed waveforms
+    pub overflows: u32,
+    /// fraction of acquisition time cut as bad blocks
+    pub cut_fraction: f32,
+}
+
+/// Per sub-block and per block counters collected in a single pass over the point.
+struct BlockStats {
+    /// trigger count per sub-block (only full sub-blocks)
+    triggers: Vec<usize>,
+    total_triggers: usize,
+    /// resets per block
+    resets: BTreeMap<usize, u32>,
+    /// overflows per block
+    overflows: BTreeMap<usize, u32>,
+}
+
+impl BlockStats {
+    fn collect(
+        waveforms: &NumassWaveformsCow,
+        acquisition_time: u64,
+        algo: &Algorithm,
+        params: &BadBlockParams,
+    ) -> Self {
+        // counts are always collected for quality metrics, criteria are applied in find_bad_blocks
+        let reset_detection = match algo {
+            Algorithm::Trapezoid {
+                reset_detection, ..
+            }
+            | Algorithm::LongDiff { reset_detection } => Some(reset_detection),
+            _ => None,
+        };
+
+        let mut triggers = vec![0usize; (acquisition_time / params.check_bin_size) as usize];
+        let mut resets = BTreeMap::<usize, u32>::new();
+        let mut overflows = BTreeMap::<usize, u32>::new();
+        let mut total_triggers = 0;
+
+        for (time, frame) in waveforms {
+            let block_idx = (time / params.cutoff_bin_size) as usize;
+
+            total_triggers += frame.len();
+            if let Some(count) = triggers.get_mut((time / params.check_bin_size) as usize) {
+                *count += frame.len();
+            }
+
+            let overflowed = frame
+                .iter()
+                .filter(|(&channel, waveform)| detect_overflow(channel, waveform).is_some())
+                .count() as u32;
+            if overflowed > 0 {
+                *overflows.entry(block_idx).or_default() += overflowed;
+            }
+
+            if let Some(reset_detection) = reset_detection {
+                let frame = frame
+                    .iter()
+                    .map(|(&channel, waveform)| (channel, waveform.as_ref()))
+                    .collect::<NumassFrameFast>();
+                if detect_reset(&frame, reset_detection).is_some() {
+                    *resets.entry(block_idx).or_default() += 1;
+                }
+            }
+        }
+
+        Self {
+            triggers,
+            total_triggers,
+            resets,
+            overflows,
+        }
+    }
+
+    /// Trigger rate (in Hz) per block, calculated from full sub-blocks.
+    fn block_rates(&self, params: &BadBlockParams) -> Vec<f32> {
+        let mut counts = vec![];
+        for (idx, count) in self.triggers.iter().enumerate() {
+            let block_idx = sub_block_to_block(idx, params);
+            if counts.len() <= block_idx {
+                counts.resize(block_idx + 1, (0, 0));
+            }
+            counts[block_idx].0 += count;
+            counts[block_idx].1 += 1;
+        }
+        counts
+            .into_iter()
+            .map(|(triggers, sub_blocks)| {
+                if sub_blocks == 0 {
+                    0.0
+                } else {
+                    triggers as f32 / (sub_blocks as u64 * params.check_bin_size) as f32 * 1e9
+                }
+            })
+            .collect()
+    }
+
+    /// Apply enabled criteria and collect details for every bad block.
+    fn bad_block_records(
+        &self,
+        block_rates: &[f32],
+        params: &BadBlockParams,
+    ) -> BTreeMap<usize, BadBlockRecord> {
+        self.find_bad_blocks(params)
+            .into_iter()
+            .map(|(block_idx, reasons)| {
+                let sub_bin_counts = self
+                    .triggers
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, _)| sub_block_to_block(*idx, params) == block_idx)
+                    .map(|(_, count)| *count)
+                    .collect();
+                let record = BadBlockRecord {
+                    reasons,
+                    sub_bin_counts,
+                    rate: block_rates.get(block_idx).copied().unwrap_or_default(),
+                    resets: self.resets.get(&block_idx).copied().unwrap_or_default(),
+                    overflows: self.overflows.get(&block_idx).copied().unwrap_or_default(),
+                };
+                (block_idx, record)
+            })
+            .collect()
+    }
+
+    /// Apply enabled criteria, returns reasons for every bad block.
+    fn find_bad_blocks(
+        &self,
+        params: &BadBlockParams,
+    ) -> BTreeMap<usize, BTreeSet<BadBlockReason>> {
+        let mut bad_blocks = BTreeMap::<usize, BTreeSet<BadBlockReason>>::new();
+        let mut mark = |block_idx: usize, reason: BadBlockReason| {
+            bad_blocks.entry(block_idx).or_default().insert(reason);
+        };
+
+        if params.empty_bins {
+            self.triggers.iter().enumerate().for_each(|(idx, count)| {
+                if *count == 0 {
+                    mark(sub_block_to_block(idx, params), BadBlockReason::EmptyBins);
+                }
+            });
+        }
+
+        if let Some(threshold) = params
+            .rate_spike_sigma
+            .and_then(|sigma| rate_spike_threshold(&self.triggers, sigma as f32))
+        {
+            self.triggers.iter().enumerate().for_each(|(idx, count)| {
+                if *count as f32 > threshold {
+                    mark(sub_block_to_block(idx, params), BadBlockReason::RateSpike);
+                }
+            });
+        }
+
+        if let Some(max_resets) = params.max_resets {
+            self.resets
+                .iter()
+                .filter(|(_, count)| **count > max_resets)
+                .for_each(|(block_idx, _)| mark(*block_idx, BadBlockReason::ResetStorm));
+        }
+
+        if let Some(max_overflows) = params.max_overflows {
+            self.overflows
+                .iter()
+                .filter(|(_, count)| **count > max_overflows)
+                .for_each(|(block_idx, _)| mark(*block_idx, BadBlockReason::OverflowBurst));
+        }
+
+        bad_blocks
+    }
+}
+
+/// Total time of `bad_blocks` (in ns), blocks are clipped to `acquisition_time`.
+fn cut_time(bad_blocks: &BTreeSet<usize>, cutoff_bin_size: u64, acquisition_time: u64) -> u64 {
+    bad_blocks
+        .iter()
+        .map(|&block| {
+            acquisition_time
+                .saturating_sub(block as u64 * cutoff_bin_size)
+                .min(cutoff_bin_size)
+        })
+        .sum()
+}
+
+fn sub_block_to_block(idx: usize, params: &BadBlockParams) -> usize {
+    ((idx as u64 * params.check_bin_size) / params.cutoff_bin_size) as usize
+}
+
+/// Trigger count above which sub-block is considered as a rate spike.
+///
+/// Expected count is estimated by median (so it is not biased by spikes themselves)
+/// and assumed to be Poisson distributed: `threshold = median + sigma * sqrt(median)`.
+/// Returns `None` if there are no sub-blocks.
+fn rate_spike_threshold(triggers: &[usize], sigma: f32) -> Option<f32> {
+    if triggers.is_empty() {
+        return None;
+    }
+    let mut sorted = triggers.to_vec();
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2] as f32;
+    // at least one trigger is expected to avoid zero threshold for sparse points
+    let expected = median.max(1.0);
+    Some(expected + sigma * expected.sqrt())
 }
 
 pub fn emulate_fir(waveform: &[i16], right: usize, center: usize, left: usize) -> Vec<f32> {
@@ -162,9 +535,17 @@ pub fn emulate_fir(waveform: &[i16], right: usize, center: usize, left: usize) -
 }
 
 /// convert point to amplitudes histogram
-/// used in [baseline_from_point]
+/// used in [baseline_from_waveforms]
 /// extracted into single function for easier testing
-pub fn point_to_amp_hist(point: &rsb_event::Point, algo: &Algorithm) -> PointHistogram {
+pub fn point_to_amp_hist(
+    point: &rsb_event::Point,
+    algo: &Algorithm,
+) -> Result<PointHistogram, String> {
+    Ok(waveforms_to_amp_hist(&extract_waveforms(point)?, algo))
+}
+
+/// same as [point_to_amp_hist] but for already extracted waveforms
+pub fn waveforms_to_amp_hist(waveforms: &NumassWaveformsCow, algo: &Algorithm) -> PointHistogram {
     let (left, center, right) = match algo {
         Algorithm::Trapezoid {
             left,
@@ -175,12 +556,10 @@ pub fn point_to_amp_hist(point: &rsb_event::Point, algo: &Algorithm) -> PointHis
         _ => panic!("not implemented"),
     };
 
-    let waveforms = extract_waveforms(point);
-
     let mut amps = PointHistogram::new_step(2.0..120.0, 0.5);
 
-    for (_, frames) in waveforms {
-        for (channel, waveform) in frames {
+    for frames in waveforms.values() {
+        for (&channel, waveform) in frames {
             let filtered = emulate_fir(waveform, right, center, left);
             amps.add_batch(channel, filtered);
         }
@@ -189,30 +568,66 @@ pub fn point_to_amp_hist(point: &rsb_event::Point, algo: &Algorithm) -> PointHis
     amps
 }
 
-pub fn frame_to_waveform(frame: &Frame) -> &[i16] {
-    unsafe {
-        std::slice::from_raw_parts(
-            frame.data.as_ptr() as *const i16,
-            frame.data.len() / 2,
-        )
-    }
+/// get frame waveform (no copy if possible, see [waveform_from_bytes]).
+pub fn frame_to_waveform(frame: &Frame) -> Result<Cow<'_, [i16]>, String> {
+    waveform_from_bytes(&frame.data)
 }
 
-/// remap waveforms from protobuf message to more convenient format (no copy).
-pub fn extract_waveforms(point: &rsb_event::Point) -> NumassWaveformsFast {
+/// remap waveforms from protobuf message to more convenient format (no copy if possible).
+/// Fails if any frame has invalid data (see [frame_to_waveform]),
+/// same as [MappedPoint::waveforms](crate::mmap::MappedPoint::waveforms).
+pub fn extract_waveforms(point: &rsb_event::Point) -> Result<NumassWaveformsCow<'_>, String> {
     let mut waveforms = BTreeMap::new();
 
     for channel in &point.channels {
         for block in &channel.blocks {
             for frame in &block.frames {
-                let entry = waveforms
-                    .entry(correct_frame_time(frame.time))
-                    .or_insert(BTreeMap::new());
-                entry.insert(channel.id as u8, frame_to_waveform(frame));
+                let time = correct_frame_time(frame.time);
+                let waveform = frame_to_waveform(frame).map_err(|err| {
+                    format!("invalid frame {time} of channel {}: {err}", channel.id)
+                })?;
+                let entry = waveforms.entry(time).or_insert(BTreeMap::new());
+                entry.insert(channel.id as u8, waveform);
             }
         }
     }
+    Ok(waveforms)
+}
+
+/// borrow waveforms from [NumassWaveformsCow] (no copy).
+pub fn waveforms_cow_borrow<'a>(waveforms: &'a NumassWaveformsCow) -> NumassWaveformsFast<'a> {
     waveforms
+        .iter()
+        .map(|(&time, channels)| {
+            (
+                time,
+                channels
+                    .iter()
+                    .map(|(&channel, waveform)| (channel, waveform.as_ref()))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+        })
+        .collect::<BTreeMap<_, _>>()
+}
+
+/// Convert little-endian waveform bytes to samples.
+/// Samples are borrowed if `data` is properly aligned (and target is little-endian), otherwise copied.
+/// Returns error if `data` has odd length.
+pub fn waveform_from_bytes(data: &[u8]) -> Result<Cow<'_, [i16]>, String> {
+    if !data.len().is_multiple_of(2) {
+        return Err(format!("waveform data has odd length {}", data.len()));
+    }
+    let len = data.len() / 2;
+    Ok(if cfg!(target_endian = "little") && data.as_ptr().align_offset(std::mem::align_of::<i16>()) == 0 {
+        // SAFETY: pointer is aligned, length is in bounds and any bit pattern is valid i16
+        Cow::Borrowed(unsafe { std::slice::from_raw_parts(data.as_ptr() as *const i16, len) })
+    } else {
+        Cow::Owned(
+            data.chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+                .collect(),
+        )
+    })
 }
 
 pub fn waveforms_fast_copy(waveforms: NumassWaveformsFast) -> NumassWaveforms {
@@ -233,10 +648,10 @@ pub fn waveforms_fast_copy(waveforms: NumassWaveformsFast) -> NumassWaveforms {
 /// extact baseline for channels from point
 /// each channel is converted to amplitude histogramm
 /// and then baseline is calculated as histogramm peak
-fn baseline_from_point(point: &rsb_event::Point, algo: &Algorithm) -> [f32; 7] {
+fn baseline_from_waveforms(waveforms: &NumassWaveformsCow, algo: &Algorithm) -> [f32; 7] {
     let mut baselines = [0.0; 7];
 
-    let amps = point_to_amp_hist(point, algo);
+    let amps = waveforms_to_amp_hist(waveforms, algo);
 
     for (ch, hist) in amps.channels {
         let mut max_idx = 0;
@@ -251,3 +666,183 @@ fn baseline_from_point(point: &rsb_event::Point, algo: &Algorithm) -> [f32; 7] {
 
     baselines
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waveform_aligned_and_misaligned() {
+        let samples = [0i16, 1, -1, i16::MAX, i16::MIN, 1234, -4321];
+        let bytes = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        // shifts 0 and 1 of the same buffer give one aligned and one misaligned slice
+        let mut buffer = vec![0u8; bytes.len() + 1];
+        let mut borrowed = 0;
+        for shift in [0, 1] {
+            let data = &mut buffer[shift..shift + bytes.len()];
+            data.copy_from_slice(&bytes);
+            let aligned = data.as_ptr().align_offset(std::mem::align_of::<i16>()) == 0;
+
+            let waveform = waveform_from_bytes(data).unwrap();
+            assert_eq!(waveform.as_ref(), &samples);
+            assert_eq!(
+                matches!(waveform, Cow::Borrowed(_)),
+                aligned && cfg!(target_endian = "little")
+            );
+            borrowed += aligned as usize;
+        }
+        assert_eq!(borrowed, 1);
+    }
+
+    #[test]
+    fn bad_block_params_validation() {
+        let params = BadBlockParams::default();
+        assert!(params.validate().is_ok());
+        for (cutoff_bin_size, check_bin_size) in [(0, 0), (CUTOFF_BIN_SIZE, 0), (10, 20)] {
+            let params = BadBlockParams {
+                cutoff_bin_size,
+                check_bin_size,
+                ..params
+            };
+            assert!(params.validate().is_err());
+
+            // params built in code are rejected by processing instead of panicking
+            let waveforms = NumassWaveformsCow::new();
+            let err = Preprocess::from_waveforms(None, &waveforms, &Algorithm::default(), &params)
+                .unwrap_err();
+            assert!(err.starts_with("invalid bad block params"), "{err}");
+            let process = crate::process::ProcessParams {
+                bad_blocks: params,
+                ..Default::default()
+            };
+            assert!(
+                crate::process::extract_waveforms_events_iter(None, waveforms, &process).is_err()
+            );
+
+            let json = serde_json::to_string(&params).unwrap();
+            assert!(serde_json::from_str::<BadBlockParams>(&json).is_err());
+        }
+
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(serde_json::from_str::<BadBlockParams>(&json).unwrap(), params);
+    }
+
+    #[test]
+    fn effective_time_clips_last_block() {
+        let mut preprocess = Preprocess {
+            baseline: None,
+            hv: 0.0,
+            start_time: NaiveDateTime::default(),
+            acquisition_time: 2_500,
+            frame_len: 0,
+            bad_blocks: BTreeSet::new(),
+            cutoff_bin_size: 1_000,
+            bad_block_records: BTreeMap::new(),
+            quality: QualityMetrics::default(),
+            block_rates: vec![],
+        };
+        assert_eq!(preprocess.effective_time(), 2_500);
+
+        // last block is only 500 ns long
+        preprocess.bad_blocks = BTreeSet::from([0, 2]);
+        assert_eq!(preprocess.effective_time(), 1_000);
+
+        preprocess.bad_blocks = BTreeSet::from([0, 1, 2, 3]);
+        assert_eq!(preprocess.effective_time(), 0);
+    }
+
+    #[test]
+    fn block_stats_counts_resets_and_overflows() {
+        // channel 0 has a reset (drop larger than threshold), channel 1 is overflowed
+        let mut reset = vec![1000i16; 5];
+        reset.resize(16, 0);
+        let overflow = vec![8189i16; 16];
+        let waveforms = NumassWaveformsCow::from([
+            (
+                0,
+                BTreeMap::from([(0u8, Cow::Owned(reset)), (1, Cow::Owned(overflow.clone()))]),
+            ),
+            (
+                CUTOFF_BIN_SIZE + 5,
+                BTreeMap::from([(1u8, Cow::Owned(overflow))]),
+            ),
+        ]);
+        let algo = Algorithm::default();
+
+        // counts are collected with disabled criteria
+        let params = BadBlockParams {
+            empty_bins: false,
+            ..BadBlockParams::default()
+        };
+        let stats = BlockStats::collect(&waveforms, 2 * CUTOFF_BIN_SIZE, &algo, &params);
+        assert_eq!(stats.resets, BTreeMap::from([(0, 1)]));
+        assert_eq!(stats.overflows, BTreeMap::from([(0, 1), (1, 1)]));
+        assert!(stats.find_bad_blocks(&params).is_empty());
+
+        let params = BadBlockParams {
+            max_resets: Some(0),
+            ..params
+        };
+        let bad_blocks = stats.find_bad_blocks(&params);
+        assert_eq!(
+            bad_blocks,
+            BTreeMap::from([(0, BTreeSet::from([BadBlockReason::ResetStorm]))])
+        );
+
+        let params = BadBlockParams {
+            max_overflows: Some(0),
+            ..params
+        };
+        let records = stats.bad_block_records(&stats.block_rates(&params), &params);
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[&0].resets, records[&0].overflows), (1, 1));
+        assert_eq!((records[&1].resets, records[&1].overflows), (0, 1));
+        assert_eq!(
+            records[&1].reasons,
+            BTreeSet::from([BadBlockReason::OverflowBurst])
+        );
+    }
+
+    #[test]
+    fn waveform_odd_length() {
+        let bytes = [0u8; 8];
+        assert!(waveform_from_bytes(&bytes[..7]).is_err());
+        assert!(waveform_from_bytes(&bytes[1..]).is_err());
+        assert_eq!(waveform_from_bytes(&bytes[..0]).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn extract_waveforms_rejects_odd_frames() {
+        use rsb_event::point::{channel::Block, Channel};
+
+        let frame = |time, len| Frame {
+            time,
+            data: vec![0; len],
+            ..Default::default()
+        };
+        let mut point = rsb_event::Point {
+            channels: vec![Channel {
+                id: 3,
+                blocks: vec![Block {
+                    frames: vec![frame(100, 16), frame(200, 16)],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(extract_waveforms(&point).unwrap().len(), 2);
+
+        point.channels[0].blocks[0].frames[1] = frame(200, 15);
+        let err = extract_waveforms(&point).unwrap_err();
+        assert!(err.starts_with("invalid frame 200 of channel 3"), "{err}");
+        let bad_blocks = BadBlockParams::default();
+        let err =
+            Preprocess::from_point(None, &point, &Algorithm::default(), &bad_blocks).unwrap_err();
+        assert!(err.starts_with("invalid frame"), "{err}");
+    }
+}
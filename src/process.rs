@@ -21,8 +21,8 @@ use crate::{
         KEV_COEFF_FIRST_PEAK, KEV_COEFF_LIKHOVID, KEV_COEFF_LONGDIFF, KEV_COEFF_MAX,
         KEV_COEFF_TRAPEZIOD,
     },
-    preprocess::{emulate_fir, extract_waveforms, Preprocess},
-    types::{FrameEvent, NumassEvent, NumassEvents, NumassFrameFast},
+    preprocess::{emulate_fir, extract_waveforms, BadBlockParams, Preprocess},
+    types::{EventOffset, FrameEvent, NumassEvent, NumassEvents, NumassFrameFast, NumassWaveformsCow},
 };
 
 #[repr(C)]
@@ -110,6 +110,8 @@ impl Default for Algorithm {
 pub struct ProcessParams {
     pub algorithm: Algorithm,
     pub convert_to_kev: bool,
+    #[serde(default)]
+    pub bad_blocks: BadBlockParams,
 }
 
 impl Default for ProcessParams {
@@ -117,6 +119,7 @@ impl Default for ProcessParams {
         Self {
             algorithm: Algorithm::default(),
             convert_to_kev: true,
+            bad_blocks: BadBlockParams::default(),
         }
     }
 }
@@ -124,45 +127,75 @@ impl Default for ProcessParams {
 /// Built-in processing algorithm.
 /// Function will extract events point wafevorms and keeps its hierarchy.
 /// Do not use this function directly without reason, use [process_point](crate::storage::process_point) instead.
+/// Fails if point can't be preprocessed (see [Preprocess::from_waveforms]).
 pub fn extract_events(
     meta: Option<NumassMeta>,
     point: rsb_event::Point,
     params: &ProcessParams,
-) -> (NumassEvents, Preprocess) {
-    let (preprocess, point) = {
-        (
-            Preprocess::from_point(meta, &point, &params.algorithm),
-            extract_waveforms(&point),
-        )
+) -> Result<(NumassEvents, Preprocess), String> {
+    let (preprocess, frames) = extract_events_iter(meta, &point, params)?;
+    Ok((frames.collect::<BTreeMap<_, _>>(), preprocess))
+}
+
+/// Streaming version of [extract_events].
+/// Returns point [Preprocess] and iterator over processed frames (sorted by time),
+/// so events can be consumed without collecting them for the whole point.
+pub fn extract_events_iter<'a>(
+    meta: Option<NumassMeta>,
+    point: &'a rsb_event::Point,
+    params: &'a ProcessParams,
+) -> Result<(Preprocess, impl Iterator<Item = (u64, Vec<NumassEvent>)> + 'a), String> {
+    extract_waveforms_events_iter(meta, extract_waveforms(point)?, params)
+}
+
+/// Same as [extract_events_iter] but for already extracted waveforms
+/// (e.g. from [MappedPoint](crate::mmap::MappedPoint)).
+pub fn extract_waveforms_events_iter<'a>(
+    meta: Option<NumassMeta>,
+    waveforms: NumassWaveformsCow<'a>,
+    params: &'a ProcessParams,
+) -> Result<(Preprocess, impl Iterator<Item = (u64, Vec<NumassEvent>)> + 'a), String> {
+    let preprocess =
+        Preprocess::from_waveforms(meta, &waveforms, &params.algorithm, &params.bad_blocks)?;
+
+    let frames = {
+        let preprocess = preprocess.clone();
+        waveforms.into_iter().map(move |(time, frame)| {
+            let frame = frame
+                .iter()
+                .map(|(&channel, waveform)| (channel, waveform.as_ref()))
+                .collect::<NumassFrameFast>();
+            (time, process_frame(&frame, params, &preprocess))
+        })
     };
 
-    (
-        point
-            .into_iter()
-            .map(|(time, frame)| {
-                let mut events = frame_to_events(
-                    &frame,
-                    &params.algorithm,
-                    Some(&preprocess),
-                    #[cfg(feature = "egui")]
-                    &mut None,
-                );
-                if params.convert_to_kev {
-                    events.iter_mut().for_each(|(_, event)| {
-                        if let FrameEvent::Event {
-                            amplitude, channel, ..
-                        } = event
-                        {
-                            *amplitude = convert_to_kev(amplitude, *channel, &params.algorithm);
-                        }
-                    });
-                }
-                
-                (time, events)
-            })
-            .collect::<BTreeMap<_, _>>(),
-        preprocess,
-    )
+    Ok((preprocess, frames))
+}
+
+/// Extract events from the frame and convert them to keV (if enabled in `params`).
+fn process_frame(
+    frame: &NumassFrameFast,
+    params: &ProcessParams,
+    preprocess: &Preprocess,
+) -> Vec<NumassEvent> {
+    let mut events = frame_to_events(
+        frame,
+        &params.algorithm,
+        Some(preprocess),
+        #[cfg(feature = "egui")]
+        &mut None,
+    );
+    if params.convert_to_kev {
+        events.iter_mut().for_each(|(_, event)| {
+            if let FrameEvent::Event {
+                amplitude, channel, ..
+            } = event
+            {
+                *amplitude = convert_to_kev(amplitude, *channel, &params.algorithm);
+            }
+        });
+    }
+    events
 }
 
 /// Built-in keV convertion (according to crate::constants).
@@ -232,7 +265,7 @@ pub fn frame_to_events(
                     .unwrap();
 
                 (
-                    x as u16 * 8,
+                    x as EventOffset * 8,
                     FrameEvent::Event {
                         channel: *ch_id,
                         amplitude: *y as f32,
@@ -261,7 +294,7 @@ pub fn frame_to_events(
                 };
 
                 (
-                    x as u16 * 8,
+                    x as EventOffset * 8,
                     FrameEvent::Event {
                         channel: *ch_id,
                         amplitude,
@@ -280,7 +313,7 @@ pub fn frame_to_events(
                         // let length = (waveform.0.len() - pos) as f32;
                         let amplitude = waveform[left..waveform.len()].iter().sum::<i16>();
                         (
-                            pos as u16 * 8,
+                            pos as EventOffset * 8,
                             FrameEvent::Event {
                                 channel: *ch_id,
                                 amplitude: amplitude as f32 / 50.0,
@@ -322,48 +355,22 @@ pub fn frame_to_events(
                 .flat_map(|(ch_id, waveform)| {
                     let mut events = vec![];
 
-                    if ch_id == &1 {
-                        if let Some((idx, _)) =
-                            waveform.iter().enumerate().find(|(_, &val)| val == 8189)
-                        {
-                            let end = if let Some((reset_start, _)) = reset {
-                                reset_start
-                            } else {
-                                waveform.len()
-                            };
-
-                            bad_frame = true;
-
-                            events.push((
-                                idx as u16 * 8,
-                                FrameEvent::Overflow {
-                                    channel: *ch_id,
-                                    size: end.abs_diff(idx) as u16,
-                                },
-                            ));
-                        }
-                    }
+                    if let Some(idx) = detect_overflow(*ch_id, waveform) {
+                        let end = if let Some((reset_start, _)) = reset {
+                            reset_start
+                        } else {
+                            waveform.len()
+                        };
 
-                    if ch_id == &5 {
-                        if let Some((idx, _)) =
-                            waveform.iter().enumerate().find(|(_, &val)| val == 8081)
-                        {
-                            let end = if let Some((reset_start, _)) = reset {
-                                reset_start
-                            } else {
-                                waveform.len()
-                            };
-
-                            bad_frame = true;
-
-                            events.push((
-                                idx as u16 * 8,
-                                FrameEvent::Overflow {
-                                    channel: *ch_id,
-                                    size: end.abs_diff(idx) as u16,
-                                },
-                            ));
-                        }
+                        bad_frame = true;
+
+                        events.push((
+                            idx as EventOffset * 8,
+                            FrameEvent::Overflow {
+                                channel: *ch_id,
+                                size: end.abs_diff(idx) as u16,
+                            },
+                        ));
                     }
 
                     let offset = left + center + right;
@@ -421,7 +428,7 @@ pub fn frame_to_events(
 
                             if (event_end - i) >= min_length {
                                 events.push((
-                                    (i + offset) as u16 * 8,
+                                    (i + offset) as EventOffset * 8,
                                     FrameEvent::Event {
                                         channel: *ch_id,
                                         amplitude: energy / offset as f32,
@@ -443,7 +450,7 @@ pub fn frame_to_events(
 
             if let Some((reset_start, reset_end)) = reset {
                 events.push((
-                    reset_start as u16 * 8,
+                    reset_start as EventOffset * 8,
                     FrameEvent::Reset {
                         size: (reset_end - reset_start) as u16,
                     },
@@ -473,7 +480,7 @@ pub fn frame_to_events(
         Algorithm::LongDiff { reset_detection } => {
             let reset = detect_reset(frame, reset_detection);
 
-            let mut events: Vec<(u16, FrameEvent)> = frame
+            let mut events: Vec<NumassEvent> = frame
                 .iter()
                 .filter_map(|(ch_id, waveform)| {
                     if reset.is_some() {
@@ -537,7 +544,7 @@ pub fn frame_to_events(
 
             if let Some((reset_start, reset_end)) = reset {
                 events.push((
-                    reset_start as u16 * 8,
+                    reset_start as EventOffset * 8,
                     FrameEvent::Reset {
                         size: (reset_end - reset_start) as u16,
                     },
@@ -553,7 +560,18 @@ pub fn frame_to_events(
     events
 }
 
-fn detect_reset(frame: &NumassFrameFast, params: &HWResetParams) -> Option<(usize, usize)> {
+/// Find position of the first overflowed sample in the waveform.
+/// Overflow values are known only for channels 2 and 6 (indices 1 and 5).
+pub(crate) fn detect_overflow(ch_id: u8, waveform: &[i16]) -> Option<usize> {
+    let overflow = match ch_id {
+        1 => 8189,
+        5 => 8081,
+        _ => return None,
+    };
+    waveform.iter().position(|&val| val == overflow)
+}
+
+pub(crate) fn detect_reset(frame: &NumassFrameFast, params: &HWResetParams) -> Option<(usize, usize)> {
     let HWResetParams {
         window,
         treshold,
@@ -0,0 +1,400 @@
+//! # Reader
+//! Lazy block-by-block point reader.
+//!
+//! [load_point](crate::storage::load_point) decodes the whole `rsb_event::Point` at once.
+//! [PointReader] instead scans protobuf wire format once to build an index of blocks
+//! (without decoding frames) and then decodes blocks on demand in time order,
+//! so the first frames of a huge point are available immediately and memory stays bounded.
+//!
+//! Blocks of different channels with the same block time are merged into one group.
+//! It is assumed that groups do not overlap in time (true for points acquired by numass DAQ).
+use std::{
+    collections::BTreeMap,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+use numass::{protos::rsb_event::point::channel::Block, NumassMeta};
+use protobuf::Message;
+
+use crate::{preprocess::frame_to_waveform, types::NumassWaveforms, utils::correct_frame_time};
+
+/// `rsb_event::Point.channels` field number.
+pub(crate) const POINT_CHANNELS: u32 = 1;
+/// `rsb_event::Point.Channel.id` field number.
+pub(crate) const CHANNEL_ID: u32 = 1;
+/// `rsb_event::Point.Channel.blocks` field number.
+pub(crate) const CHANNEL_BLOCKS: u32 = 2;
+/// `rsb_event::Point.Channel.Block.time` field number.
+pub(crate) const BLOCK_TIME: u32 = 1;
+/// `rsb_event::Point.Channel.Block.frames` field number.
+pub(crate) const BLOCK_FRAMES: u32 = 2;
+/// `rsb_event::Point.Channel.Block.Frame.time` field number.
+pub(crate) const FRAME_TIME: u32 = 1;
+/// `rsb_event::Point.Channel.Block.Frame.data` field number.
+pub(crate) const FRAME_DATA: u32 = 2;
+
+pub(crate) const WIRE_VARINT: u32 = 0;
+pub(crate) const WIRE_FIXED64: u32 = 1;
+pub(crate) const WIRE_LEN: u32 = 2;
+pub(crate) const WIRE_FIXED32: u32 = 5;
+
+/// Single waveform from the point (frame time, channel, waveform).
+pub type ChannelFrame = (u64, u8, Vec<i16>);
+
+/// Position of the encoded block in the file.
+#[derive(Debug, Clone)]
+struct BlockIndex {
+    channel: u8,
+    time: u64,
+    bytes: Range<u64>,
+}
+
+/// Lazy point reader (see [module docs](crate::reader)).
+pub struct PointReader<R: Read + Seek> {
+    reader: BufReader<R>,
+    meta: NumassMeta,
+    /// block groups sorted by block time
+    groups: Vec<Vec<BlockIndex>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl PointReader<std::fs::File> {
+    /// Open point file and build blocks index.
+    pub fn open(filepath: &std::path::Path) -> io::Result<Self> {
+        PointReader::new(std::fs::File::open(filepath)?)
+    }
+}
+
+impl<R: Read + Seek> PointReader<R> {
+    /// Read envelope meta and build blocks index.
+    /// `reader` must point to the start of dataforge envelope.
+    pub fn new(reader: R) -> io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+
+        let (_, meta) = dataforge::read_df_header_and_meta_sync::<NumassMeta>(&mut reader)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err:?}")))?;
+
+        let groups = index_point(&mut reader)?;
+
+        Ok(Self {
+            reader,
+            meta,
+            groups,
+        })
+    }
+
+    pub fn meta(&self) -> &NumassMeta {
+        &self.meta
+    }
+
+    /// Number of block groups in the point.
+    pub fn blocks_len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Decode block group `idx` (blocks of all channels with the same time).
+    pub fn read_block(&mut self, idx: usize) -> io::Result<NumassWaveforms> {
+        read_group(&mut self.reader, &self.groups[idx])
+    }
+
+    /// Iterate over point block by block (each item is [read_block](PointReader::read_block) result).
+    pub fn blocks(mut self) -> impl Iterator<Item = io::Result<NumassWaveforms>> {
+        (0..self.blocks_len()).map(move |idx| self.read_block(idx))
+    }
+
+    /// Iterate over point frames in time order, decoding blocks lazily.
+    pub fn frames(self) -> impl Iterator<Item = io::Result<ChannelFrame>> {
+        self.blocks().flat_map(|block| {
+            let frames: Box<dyn Iterator<Item = io::Result<ChannelFrame>>> = match block {
+                Ok(block) => Box::new(block.into_iter().flat_map(|(time, frame)| {
+                    frame
+                        .into_iter()
+                        .map(move |(channel, waveform)| Ok((time, channel, waveform)))
+                })),
+                Err(err) => Box::new(std::iter::once(Err(err))),
+            };
+            frames
+        })
+    }
+}
+
+/// Scan `rsb_event::Point` message (from the current position to the end)
+/// and return its blocks positions grouped by block time.
+fn index_point<R: Read + Seek>(reader: &mut BufReader<R>) -> io::Result<Vec<Vec<BlockIndex>>> {
+    let data_start = reader.stream_position()?;
+    let data_end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(data_start))?;
+
+    let mut blocks = vec![];
+    let mut pos = data_start;
+    while pos < data_end {
+        let (field, wire) = read_tag(reader)?;
+        if field == POINT_CHANNELS && wire == WIRE_LEN {
+            let len = read_varint(reader)?;
+            let start = reader.stream_position()?;
+            if start + len > data_end {
+                return Err(truncated());
+            }
+            blocks.extend(index_channel(reader, start..start + len)?);
+        } else {
+            skip_field(reader, wire)?;
+        }
+        pos = reader.stream_position()?;
+    }
+
+    let mut groups = BTreeMap::<u64, Vec<BlockIndex>>::new();
+    for block in blocks {
+        groups.entry(block.time).or_default().push(block);
+    }
+    Ok(groups.into_values().collect())
+}
+
+/// Decode blocks of the group (see [PointReader::read_block]).
+fn read_group<R: Read + Seek>(
+    reader: &mut BufReader<R>,
+    group: &[BlockIndex],
+) -> io::Result<NumassWaveforms> {
+    let mut waveforms = NumassWaveforms::new();
+
+    for BlockIndex { channel, bytes, .. } in group {
+        reader.seek(SeekFrom::Start(bytes.start))?;
+        let mut buf = vec![0; (bytes.end - bytes.start) as usize];
+        reader.read_exact(&mut buf)?;
+
+        let block = Block::parse_from_bytes(&buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err:?}")))?;
+
+        for frame in &block.frames {
+            let time = correct_frame_time(frame.time);
+            let waveform = frame_to_waveform(frame).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid frame {time} of channel {channel}: {err}"),
+                )
+            })?;
+            waveforms
+                .entry(time)
+                .or_default()
+                .insert(*channel, waveform.into_owned());
+        }
+    }
+
+    Ok(waveforms)
+}
+
+/// Scan `rsb_event::Point.Channel` message and return its blocks positions.
+fn index_channel<R: Read + Seek>(
+    reader: &mut BufReader<R>,
+    bytes: Range<u64>,
+) -> io::Result<Vec<BlockIndex>> {
+    let mut channel = 0;
+    let mut blocks = vec![];
+
+    let mut pos = bytes.start;
+    while pos < bytes.end {
+        let (field, wire) = read_tag(reader)?;
+        if field == CHANNEL_ID && wire == WIRE_VARINT {
+            channel = read_varint(reader)? as u8;
+        } else if field == CHANNEL_BLOCKS && wire == WIRE_LEN {
+            let len = read_varint(reader)?;
+            let start = reader.stream_position()?;
+            if start + len > bytes.end {
+                return Err(truncated());
+            }
+
+            // time is the first field of the block (zero time is not encoded, block can be empty)
+            let tag = if len > 0 {
+                Some(read_tag(reader)?)
+            } else {
+                None
+            };
+            let time = match tag {
+                Some((BLOCK_TIME, WIRE_VARINT)) => read_varint(reader)?,
+                _ => 0,
+            };
+
+            blocks.push((time, start..start + len));
+            reader.seek(SeekFrom::Start(start + len))?;
+        } else {
+            skip_field(reader, wire)?;
+        }
+        pos = reader.stream_position()?;
+    }
+
+    // channel id can be encoded after blocks, so it is applied at the end
+    Ok(blocks
+        .into_iter()
+        .map(|(time, bytes)| BlockIndex {
+            channel,
+            time,
+            bytes,
+        })
+        .collect())
+}
+
+pub(crate) fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "varint is too long"))
+}
+
+/// Read protobuf field tag as (field number, wire type).
+pub(crate) fn read_tag<R: Read>(reader: &mut R) -> io::Result<(u32, u32)> {
+    let tag = read_varint(reader)?;
+    Ok(((tag >> 3) as u32, (tag & 0x7) as u32))
+}
+
+/// Skip field body with wire type `wire` (tag must be already read).
+pub(crate) fn skip_field<R: Read>(reader: &mut R, wire: u32) -> io::Result<()> {
+    let len = match wire {
+        WIRE_VARINT => return read_varint(reader).map(|_| ()),
+        WIRE_FIXED64 => 8,
+        WIRE_LEN => read_varint(reader)?,
+        WIRE_FIXED32 => 4,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported wire type {wire}"),
+            ))
+        }
+    };
+    if io::copy(&mut reader.by_ref().take(len), &mut io::sink())? < len {
+        return Err(truncated());
+    }
+    Ok(())
+}
+
+/// Split length-delimited field body from the buffer (in-memory counterpart of `read_varint` + read).
+pub(crate) fn take_len<'a>(buf: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = read_varint(buf)? as usize;
+    if buf.len() < len {
+        return Err(truncated());
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "field is truncated")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use numass::protos::rsb_event::{
+        self,
+        point::{channel::block::Frame, Channel},
+    };
+
+    use super::*;
+    use crate::preprocess::extract_waveforms;
+
+    /// Point with 3 channels (channel 0 has default id that is not encoded) and 3 blocks per channel.
+    /// Frames of the block are stored in reverse time order.
+    pub(crate) fn point() -> rsb_event::Point {
+        let channels = [0u64, 3, 5]
+            .into_iter()
+            .map(|id| Channel {
+                id,
+                blocks: (0..3u64)
+                    .map(|block| Block {
+                        time: block * 1_000_000,
+                        frames: (0..4u64)
+                            .rev()
+                            .map(|frame| Frame {
+                                time: block * 1_000_000 + frame * 10_000 + id,
+                                data: (0..8i16)
+                                    .flat_map(|sample| {
+                                        (sample * (id as i16 + 1) - frame as i16).to_le_bytes()
+                                    })
+                                    .collect(),
+                                ..Default::default()
+                            })
+                            .collect(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+        rsb_event::Point {
+            channels,
+            ..Default::default()
+        }
+    }
+
+    /// Frames of the point as [PointReader::frames] would produce them (without envelope).
+    fn read_frames(data: Vec<u8>) -> io::Result<Vec<ChannelFrame>> {
+        let mut reader = BufReader::new(Cursor::new(data));
+        let mut frames = vec![];
+        for group in index_point(&mut reader)? {
+            for (time, frame) in read_group(&mut reader, &group)? {
+                frames.extend(
+                    frame
+                        .into_iter()
+                        .map(|(channel, waveform)| (time, channel, waveform)),
+                );
+            }
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn frames_match_extract_waveforms() {
+        let point = point();
+        let expected = extract_waveforms(&point)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(time, frame)| {
+                frame
+                    .into_iter()
+                    .map(move |(channel, waveform)| (time, channel, waveform.into_owned()))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(expected.len(), 3 * 3 * 4);
+
+        let frames = read_frames(point.write_to_bytes().unwrap()).unwrap();
+        assert_eq!(frames, expected);
+        assert!(frames.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+
+    #[test]
+    fn truncated_point() {
+        let data = point().write_to_bytes().unwrap();
+        // cut inside the last frame data
+        assert!(read_frames(data[..data.len() - 1].to_vec()).is_err());
+        // any cut must not panic (cuts on field boundaries are valid shorter points)
+        for len in 0..data.len() {
+            let _ = read_frames(data[..len].to_vec());
+        }
+    }
+
+    #[test]
+    fn wire_helpers() {
+        let mut buf: &[u8] = &[0xac, 0x02, 0x12, 0x03, 1, 2, 3, 0x08];
+        assert_eq!(read_varint(&mut buf).unwrap(), 300);
+        assert_eq!(read_tag(&mut buf).unwrap(), (2, WIRE_LEN));
+        assert_eq!(take_len(&mut buf).unwrap(), &[1, 2, 3]);
+        assert_eq!(read_tag(&mut buf).unwrap(), (1, WIRE_VARINT));
+        assert!(read_varint(&mut buf).is_err());
+
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+        assert!(take_len(&mut &[0x05, 1, 2][..]).is_err());
+        assert!(skip_field(&mut &[0x05, 1, 2][..], WIRE_LEN).is_err());
+        assert!(skip_field(&mut &[1, 2, 3][..], WIRE_FIXED32).is_err());
+        assert!(skip_field(&mut &[0; 8][..], 3).is_err());
+        let mut buf: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 0, 42];
+        skip_field(&mut buf, WIRE_FIXED64).unwrap();
+        assert_eq!(buf, &[42]);
+    }
+}
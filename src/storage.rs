@@ -1,68 +1,69 @@
 //! # Storage
 //! High-level processing + storage functions
 //! Should work with both local and remote storage.
+//! Loading goes through [default_backend] (see [backend](crate::backend) to use another source).
 //! If possible, use functions from this module instead of [process](crate::process) and [postprocess](crate::postprocess) directly.
 use std::{
-    fs,
     path::{Path, PathBuf},
     time::SystemTime,
 };
 
+use chrono::NaiveDateTime;
 use numass::NumassMeta;
+#[cfg(not(target_arch = "wasm32"))]
 use protobuf::Message;
 use serde::{Deserialize, Serialize};
 
+#[cfg(target_arch = "wasm32")]
+use crate::backend::HttpBackend;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::backend::LocalBackend;
 use crate::{
-    numass::protos::rsb_event, postprocess::PostProcessParams, preprocess::Preprocess, process::ProcessParams, types::NumassEvents
+    backend::StorageBackend,
+    histogram::{HistogramParams, PointHistogram},
+    numass::protos::rsb_event,
+    postprocess::PostProcessParams,
+    preprocess::Preprocess,
+    process::ProcessParams,
+    types::NumassEvents,
+    utils::EventCounters,
 };
 
+/// Backend used by functions of this module (local filesystem).
+#[cfg(not(target_arch = "wasm32"))]
+pub fn default_backend() -> LocalBackend {
+    LocalBackend
+}
+
+/// Backend used by functions of this module (API of the server that serves current page).
+#[cfg(target_arch = "wasm32")]
+pub fn default_backend() -> HttpBackend {
+    HttpBackend::from_location()
+}
+
 /// Process point from the storage.
 /// This function will load point from storage (both local and remote) and executes [extract_events](crate::process::extract_events).
+/// Returns `None` if the point can't be loaded or processed (e.g. with invalid [BadBlockParams](crate::preprocess::BadBlockParams)).
 pub async fn process_point(
     filepath: &Path,
     process: &ProcessParams,
     postprocess: Option<&PostProcessParams>,
 ) -> Option<(NumassMeta, Option<(NumassEvents, Preprocess)>)> {
-    let meta = load_meta(filepath).await;
-
-    if let Some(NumassMeta::Reply(numass::Reply::AcquirePoint { .. })) = &meta {
-        #[cfg(not(target_arch = "wasm32"))]
-        {
-            // TODO: remove duplication with wasm32 branch. Maybe use async closures? https://rust-lang.github.io/async-book/07_workarounds/05_async_closures.html
-            let point = load_point(filepath).await;
-
-            let events = if let Some(postprocess) = postprocess {
-                crate::postprocess::post_process(crate::process::extract_events(meta.clone(), point, process), postprocess)
-            } else {
-                crate::process::extract_events(meta.clone(), point, process)
-            };
-
-            Some((
-                meta.clone().unwrap(),
-                Some(events),
-            ))
-        }
+    default_backend().process(filepath, process, postprocess).await
+}
 
-        #[cfg(target_arch = "wasm32")]
-        {
-            let amplitudes_raw = gloo::net::http::Request::post(&api_url("api/process", filepath))
-                .json(&(process, postprocess))
-                .unwrap()
-                .send()
-                .await
-                .unwrap()
-                .binary()
-                .await
-                .unwrap();
-            Some((
-                meta.unwrap(),
-                rmp_serde::from_slice::<Option<(NumassEvents, Preprocess)>>(&amplitudes_raw)
-                    .unwrap(),
-            ))
-        }
-    } else {
-        None
-    }
+/// Process point from the storage directly into histogram.
+/// Unlike [process_point] events are not collected (on native targets),
+/// so memory consumption doesn't depend on the number of events in the point.
+pub async fn process_point_histogram(
+    filepath: &Path,
+    process: &ProcessParams,
+    postprocess: Option<&PostProcessParams>,
+    histogram: HistogramParams,
+) -> Option<(NumassMeta, Option<(PointHistogram, EventCounters, Preprocess)>)> {
+    default_backend()
+        .process_histogram(filepath, process, postprocess, histogram)
+        .await
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -78,45 +79,21 @@ pub fn api_url(prefix: &str, filepath: &Path) -> String {
 }
 
 /// Load point metadata only from the storage.
+/// Returns error if file can't be opened and `None` if it is not a valid dataforge message.
 #[cfg(not(target_arch = "wasm32"))]
-pub fn load_meta_sync(filepath: &Path) -> Option<NumassMeta> {
-    let mut point_file = std::fs::File::open(filepath).unwrap();
-    dataforge::read_df_header_and_meta_sync::<numass::NumassMeta>(&mut point_file)
-        .map_or(None, |(_, meta)| Some(meta))
+pub fn load_meta_sync(filepath: &Path) -> std::io::Result<Option<NumassMeta>> {
+    let mut point_file = std::fs::File::open(filepath)?;
+    Ok(dataforge::read_df_header_and_meta_sync::<numass::NumassMeta>(&mut point_file)
+        .map_or(None, |(_, meta)| Some(meta)))
 }
 
 /// Load point metadata only from the storage.
 pub async fn load_meta(filepath: &Path) -> Option<NumassMeta> {
-    #[cfg(target_arch = "wasm32")]
-    {
-        gloo::net::http::Request::get(&api_url("api/meta", filepath))
-            .send()
-            .await
-            .unwrap()
-            .json::<Option<NumassMeta>>()
-            .await
-            .unwrap()
-    }
-
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        let mut point_file = tokio::fs::File::open(&filepath).await.unwrap();
-        dataforge::read_df_header_and_meta::<numass::NumassMeta>(&mut point_file)
-            .await
-            .map_or(None, |(_, meta)| Some(meta))
-    }
+    default_backend().load_meta(filepath).await
 }
 
 pub async fn load_modified_time(filepath: PathBuf) -> Option<SystemTime> {
-    if let Ok(metadata) = fs::metadata(filepath) {
-        if let Ok(modified) = metadata.modified() {
-            Some(modified)
-        } else {
-            None
-        }
-    } else {
-        None
-    }
+    default_backend().modified(&filepath).await
 }
 
 /// Load and parse point binary data from the storage.
@@ -131,39 +108,164 @@ pub fn load_point_sync(filepath: &Path) -> rsb_event::Point {
     }
 }
 
+/// Process local point using memory-mapped file (see [MappedPoint](crate::mmap::MappedPoint)).
+/// Same as [process_point] but waveforms are not copied into memory.
+/// Returns `None` in place of events if the file is not a point (see [process_point]).
+/// Fails if the file can't be read or the point can't be processed (e.g. with invalid params).
+#[cfg(not(target_arch = "wasm32"))]
+pub fn process_point_mmap(
+    filepath: &Path,
+    process: &ProcessParams,
+    postprocess: Option<&PostProcessParams>,
+) -> std::io::Result<(NumassMeta, Option<(NumassEvents, Preprocess)>)> {
+    let point = crate::mmap::MappedPoint::open(filepath)?;
+    let meta = point.meta().clone();
+
+    if let NumassMeta::Reply(numass::Reply::AcquirePoint { .. }) = &meta {
+        let (preprocess, frames) = crate::process::extract_waveforms_events_iter(
+            Some(meta.clone()),
+            point.waveforms()?,
+            process,
+        )
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+        let events = if let Some(postprocess) = postprocess {
+            crate::postprocess::post_process_frames(frames, &preprocess, postprocess).collect()
+        } else {
+            frames.collect()
+        };
+
+        Ok((meta, Some((events, preprocess))))
+    } else {
+        Ok((meta, None))
+    }
+}
+
 /// Load and parse point binary data from the storage.
 /// Do not use this function directly without reason.
-pub async fn load_point(filepath: &Path) -> rsb_event::Point {
-    #[cfg(target_arch = "wasm32")]
-    {
-        let point_data = gloo::net::http::Request::get(&api_url("files", filepath))
-            .send()
-            .await
-            .unwrap()
-            .binary()
-            .await
-            .unwrap();
-
-        let mut buf = std::io::Cursor::new(point_data);
-        let message: dataforge::DFMessage<NumassMeta> =
-            dataforge::read_df_message_sync::<NumassMeta>(&mut buf).unwrap();
-
-        rsb_event::Point::parse_from_bytes(&message.data.unwrap()[..]).unwrap()
+pub async fn load_point(filepath: &Path) -> Option<rsb_event::Point> {
+    default_backend().load_point(filepath).await
+}
+
+/// Metadata of processed point envelope (see [save_processed_sync]).
+/// Contains params used for processing and provenance of the result.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProcessedMeta {
+    /// path to the source point
+    pub source: PathBuf,
+    pub process: ProcessParams,
+    pub postprocess: Option<PostProcessParams>,
+    /// time when the result was produced (local time)
+    pub created: NaiveDateTime,
+    /// version of this crate that produced the result
+    pub version: String,
+}
+
+impl ProcessedMeta {
+    pub fn new(
+        source: &Path,
+        process: &ProcessParams,
+        postprocess: Option<&PostProcessParams>,
+    ) -> Self {
+        Self {
+            source: source.to_owned(),
+            process: process.to_owned(),
+            postprocess: postprocess.cloned(),
+            created: chrono::Local::now().naive_local(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        if let Ok(mut point_file) = tokio::fs::File::open(&filepath).await {
-            let message = dataforge::read_df_message::<numass::NumassMeta>(&mut point_file)
-                .await
-                .unwrap();
-            rsb_event::Point::parse_from_bytes(&message.data.unwrap_or(vec![])[..]).unwrap()
-        } else {
-            panic!("{filepath:?} open failed")
+    /// Check that the result was produced with the same processing params.
+    pub fn check_params(
+        &self,
+        process: &ProcessParams,
+        postprocess: Option<&PostProcessParams>,
+    ) -> Result<(), String> {
+        if &self.process != process {
+            return Err(format!(
+                "process params mismatch: expected {process:?}, found {:?}",
+                self.process
+            ));
         }
+        if self.postprocess.as_ref() != postprocess {
+            return Err(format!(
+                "postprocess params mismatch: expected {postprocess:?}, found {:?}",
+                self.postprocess
+            ));
+        }
+        Ok(())
     }
 }
 
+/// Save processed point as dataforge envelope.
+/// Meta is [ProcessedMeta], data is msgpack encoded `(NumassEvents, Preprocess)`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save_processed_sync(
+    filepath: &Path,
+    meta: ProcessedMeta,
+    events: &NumassEvents,
+    preprocess: &Preprocess,
+) -> Result<(), String> {
+    let data = rmp_serde::to_vec(&(events, preprocess)).map_err(|err| err.to_string())?;
+    let mut file = std::fs::File::create(filepath).map_err(|err| err.to_string())?;
+    dataforge::write_df_message_sync(
+        &mut file,
+        dataforge::DFMessage {
+            meta,
+            data: Some(data),
+        },
+    )
+    .map(|_| ())
+    .map_err(|err| format!("{err:?}"))
+}
+
+/// Load processed point saved with [save_processed_sync].
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_processed_sync(
+    filepath: &Path,
+) -> Result<(ProcessedMeta, NumassEvents, Preprocess), String> {
+    let mut file = std::fs::File::open(filepath).map_err(|err| err.to_string())?;
+    let message = dataforge::read_df_message_sync::<ProcessedMeta>(&mut file)
+        .map_err(|err| format!("{err:?}"))?;
+    decode_processed(message)
+}
+
+/// Load processed point saved with [save_processed_sync] and reject it
+/// if it was produced with other params (see [ProcessedMeta::check_params]).
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_processed_checked_sync(
+    filepath: &Path,
+    process: &ProcessParams,
+    postprocess: Option<&PostProcessParams>,
+) -> Result<(NumassEvents, Preprocess), String> {
+    let (meta, events, preprocess) = load_processed_sync(filepath)?;
+    meta.check_params(process, postprocess)?;
+    Ok((events, preprocess))
+}
+
+/// Load processed point saved with [save_processed_sync] from the storage (both local and remote).
+pub async fn load_processed(
+    filepath: &Path,
+) -> Result<(ProcessedMeta, NumassEvents, Preprocess), String> {
+    let raw = default_backend()
+        .read(filepath)
+        .await
+        .ok_or(format!("{filepath:?} read failed"))?;
+    let message = dataforge::read_df_message_sync::<ProcessedMeta>(&mut std::io::Cursor::new(raw))
+        .map_err(|err| format!("{err:?}"))?;
+    decode_processed(message)
+}
+
+fn decode_processed(
+    message: dataforge::DFMessage<ProcessedMeta>,
+) -> Result<(ProcessedMeta, NumassEvents, Preprocess), String> {
+    let data = message.data.ok_or("processed envelope has no data")?;
+    let (events, preprocess) = rmp_serde::from_slice::<(NumassEvents, Preprocess)>(&data)
+        .map_err(|err| err.to_string())?;
+    Ok((message.meta, events, preprocess))
+}
+
 /// Temporal numass file storage representation.
 /// TODO: switch to real numass storage service when it will be implemented.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -179,6 +281,15 @@ impl Default for LoadState {
     }
 }
 
+/// Change in the storage tree (see [watch](crate::watch) module).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FSEvent {
+    /// new file or directory (directories are added as not loaded)
+    Added(FSRepr),
+    Modified { path: PathBuf, modified: SystemTime },
+    Removed(PathBuf),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FSRepr {
     File {
@@ -212,63 +323,105 @@ impl FSRepr {
         }
     }
 
-    pub async fn reload(self) {
-        unimplemented!()
+    /// Reload node from the storage.
+    /// Already loaded directories stay loaded (see [update_reccurently](FSRepr::update_reccurently)).
+    /// Nodes which no longer exist are left as is (they are removed on the parent reload).
+    pub async fn reload(&mut self) {
+        match self {
+            FSRepr::File { path, modified } => {
+                if let Some(FSRepr::File {
+                    modified: modified_new,
+                    ..
+                }) = FSRepr::ls(path.to_owned()).await
+                {
+                    *modified = modified_new;
+                }
+            }
+            FSRepr::Directory {
+                path,
+                modified,
+                load_state: LoadState::NotLoaded | LoadState::NeedLoad,
+                ..
+            } => {
+                if let Some(FSRepr::Directory {
+                    modified: modified_new,
+                    ..
+                }) = FSRepr::ls(path.to_owned()).await
+                {
+                    *modified = modified_new;
+                }
+            }
+            FSRepr::Directory {
+                load_state: LoadState::Loaded,
+                ..
+            } => self.update_reccurently().await,
+        }
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
-    pub async fn ls(path: PathBuf) -> FSRepr {
-        let meta = tokio::fs::metadata(&path).await.unwrap();
-
-        if meta.is_file() {
-            return FSRepr::File {
-                path,
-                modified: meta.modified().unwrap(),
-            };
+    /// Find node by path (only loaded part of the tree is searched).
+    pub fn find_mut(&mut self, target: &Path) -> Option<&mut FSRepr> {
+        let mut current = self;
+        loop {
+            if current.to_filename() == target {
+                return Some(current);
+            }
+            match current {
+                FSRepr::Directory { children, .. } => {
+                    current = children
+                        .iter_mut()
+                        .find(|child| target.starts_with(child.to_filename()))?;
+                }
+                FSRepr::File { .. } => return None,
+            }
         }
+    }
 
-        if meta.is_dir() {
-            let mut read_dir = tokio::fs::read_dir(&path).await.unwrap();
-
-            let mut children = vec![];
-            while let Ok(Some(child)) = read_dir.next_entry().await {
-                let meta = child.metadata().await.unwrap();
-                let path = child.path();
-                if meta.is_file() {
-                    children.push(FSRepr::File {
-                        path,
-                        modified: meta.modified().unwrap(),
-                    });
-                } else if meta.is_dir() {
-                    children.push(FSRepr::Directory {
-                        path,
-                        children: vec![],
-                        modified: meta.modified().unwrap(),
-                        load_state: LoadState::NotLoaded,
-                    });
+    /// Apply change from [FSWatcher](crate::watch::FSWatcher) or [FSPoller](crate::watch::FSPoller) to the tree.
+    /// Changes inside not loaded directories are ignored.
+    pub fn apply_event(&mut self, event: &FSEvent) {
+        match event {
+            FSEvent::Added(node) => {
+                let path = node.to_filename();
+                if let Some(FSRepr::Directory {
+                    children,
+                    load_state: LoadState::Loaded,
+                    ..
+                }) = path.parent().and_then(|parent| self.find_mut(parent))
+                {
+                    if children.iter().all(|child| child.to_filename() != path) {
+                        children.push(node.to_owned());
+                        children.sort_by(|v1, v2| {
+                            natord::compare(
+                                v1.to_filename().as_os_str().to_str().unwrap(),
+                                v2.to_filename().as_os_str().to_str().unwrap(),
+                            )
+                        });
+                    }
                 }
             }
-            FSRepr::Directory {
+            FSEvent::Modified {
                 path,
-                children,
-                modified: meta.modified().unwrap(),
-                load_state: LoadState::NotLoaded,
+                modified: modified_new,
+            } => match self.find_mut(path) {
+                Some(FSRepr::File { modified, .. }) | Some(FSRepr::Directory { modified, .. }) => {
+                    *modified = *modified_new
+                }
+                None => {}
+            },
+            FSEvent::Removed(path) => {
+                if let Some(FSRepr::Directory { children, .. }) =
+                    path.parent().and_then(|parent| self.find_mut(parent))
+                {
+                    children.retain(|child| &child.to_filename() != path);
+                }
             }
-        } else {
-            panic!("neither file, nor directory!")
         }
     }
 
-    #[cfg(target_arch = "wasm32")]
-    pub async fn ls(path: PathBuf) -> FSRepr {
-        let payload = gloo::net::http::Request::get(&api_url("api/ls", &path))
-            .send()
-            .await
-            .unwrap()
-            .binary()
-            .await
-            .unwrap();
-        serde_json::from_slice(&payload).unwrap() // TODO: change to Request (to remove serde-json)?
+    /// List directory (children are not loaded) or get file info (see [StorageBackend::ls]).
+    /// Returns `None` if `path` does not exist (e.g. was removed).
+    pub async fn ls(path: PathBuf) -> Option<FSRepr> {
+        default_backend().ls(&path).await
     }
 
     pub async fn expand(
@@ -279,11 +432,11 @@ impl FSRepr {
     ) {
         *load_state = LoadState::Loaded;
         let updated = FSRepr::ls(path.to_owned()).await;
-        if let FSRepr::Directory {
+        if let Some(FSRepr::Directory {
             children: children_upd,
             modified: modified_upd,
             ..
-        } = updated
+        }) = updated
         {
             let mut to_merge = children_upd
                 .into_iter()
@@ -359,11 +512,11 @@ impl FSRepr {
                 {
                     match load_state {
                         LoadState::Loaded => {
-                            if let FSRepr::Directory {
+                            if let Some(FSRepr::Directory {
                                 children: mut children_new,
                                 modified: modified_new,
                                 ..
-                            } = FSRepr::ls(path.to_owned()).await
+                            }) = FSRepr::ls(path.to_owned()).await
                             {
                                 children_new.iter_mut().for_each(|child_new| {
                                     if let Some(child) = children.iter().find(|child| {
@@ -404,3 +557,89 @@ impl FSRepr {
         }
     }
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use super::*;
+    use crate::{preprocess::CUTOFF_BIN_SIZE, types::FrameEvent};
+
+    fn processed() -> (NumassEvents, Preprocess) {
+        let events = NumassEvents::from([
+            (
+                0,
+                vec![(
+                    10,
+                    FrameEvent::Event {
+                        channel: 5,
+                        amplitude: 12.5,
+                        size: 8,
+                    },
+                )],
+            ),
+            (
+                CUTOFF_BIN_SIZE,
+                vec![(
+                    0,
+                    FrameEvent::Overflow {
+                        channel: 1,
+                        size: 4,
+                    },
+                )],
+            ),
+        ]);
+        let preprocess = Preprocess {
+            baseline: Some([1.0; 7]),
+            hv: 14000.0,
+            start_time: NaiveDateTime::default(),
+            acquisition_time: 2 * CUTOFF_BIN_SIZE,
+            frame_len: 1000,
+            bad_blocks: BTreeSet::from([1]),
+            cutoff_bin_size: CUTOFF_BIN_SIZE,
+            bad_block_records: BTreeMap::new(),
+            quality: Default::default(),
+            block_rates: vec![1.0, 1.0],
+        };
+        (events, preprocess)
+    }
+
+    #[test]
+    fn processed_round_trip() {
+        let filepath =
+            std::env::temp_dir().join(format!("processing-storage-{}.df", std::process::id()));
+        let (events, preprocess) = processed();
+        let process = ProcessParams::default();
+        let postprocess = PostProcessParams::default();
+        let meta = ProcessedMeta::new(Path::new("/data/set_1/p0"), &process, Some(&postprocess));
+
+        save_processed_sync(&filepath, meta.clone(), &events, &preprocess).unwrap();
+        let (loaded_meta, loaded_events, loaded_preprocess) =
+            load_processed_sync(&filepath).unwrap();
+        assert_eq!(loaded_meta, meta);
+        assert_eq!(
+            serde_json::to_value(&loaded_events).unwrap(),
+            serde_json::to_value(&events).unwrap()
+        );
+        assert_eq!(
+            serde_json::to_value(&loaded_preprocess).unwrap(),
+            serde_json::to_value(&preprocess).unwrap()
+        );
+
+        assert!(load_processed_checked_sync(&filepath, &process, Some(&postprocess)).is_ok());
+
+        let other_process = ProcessParams {
+            convert_to_kev: false,
+            ..process.clone()
+        };
+        let err =
+            load_processed_checked_sync(&filepath, &other_process, Some(&postprocess)).unwrap_err();
+        assert!(err.starts_with("process params mismatch"), "{err}");
+
+        let err = load_processed_checked_sync(&filepath, &process, None).unwrap_err();
+        assert!(err.starts_with("postprocess params mismatch"), "{err}");
+
+        std::fs::remove_file(&filepath).unwrap();
+        assert!(load_processed_sync(&filepath).is_err());
+    }
+}
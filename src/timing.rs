@@ -0,0 +1,236 @@
+//! # Timing
+//! Absolute event times and time-interval (Δt) distribution.
+//!
+//! For uncorrelated events intervals between consecutive events are distributed exponentially
+//! with the slope equal to the event rate. Deviations (excess at small Δt, rate different from
+//! the counted one) indicate correlated noise or dead time problems.
+//! ```no_run
+//! # use processing::{timing::*, types::NumassEvents};
+//! # let events = NumassEvents::new();
+//! let intervals = time_intervals(&event_times(&events, None));
+//! let distribution = IntervalDistribution::new(&intervals, 0.0..1000.0, 200, 10.0..1000.0);
+//! if let Some(fit) = distribution.fit {
+//!     println!("rate: {} ± {} Hz (chi2/ndf = {})", fit.rate, fit.rate_error, fit.chi2_ndf);
+//! }
+//! ```
+//! Event times are `frame_time + offset`, so they are exact only for events without
+//! [merge_frames](crate::postprocess::PostProcessParams::merge_frames) postprocessing.
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    histogram::PointHistogram,
+    types::{EventOffset, FrameEvent, NumassEvents},
+};
+
+/// Absolute event time in ns from the point start.
+pub fn absolute_time(frame_time: u64, offset: EventOffset) -> u64 {
+    frame_time + offset as u64
+}
+
+/// Sorted absolute times (in ns) of [FrameEvent::Event]s (only of `channel` if set).
+pub fn event_times(events: &NumassEvents, channel: Option<u8>) -> Vec<u64> {
+    let mut times = events
+        .iter()
+        .flat_map(|(frame_time, events)| {
+            events
+                .iter()
+                .filter_map(move |(offset, event)| match event {
+                    FrameEvent::Event { channel: ch, .. }
+                        if channel.is_none_or(|channel| channel == *ch) =>
+                    {
+                        Some(absolute_time(*frame_time, *offset))
+                    }
+                    _ => None,
+                })
+        })
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+    times
+}
+
+/// Times of triggers (frames) in ns.
+pub fn trigger_times(events: &NumassEvents) -> Vec<u64> {
+    events.keys().copied().collect()
+}
+
+/// Intervals between consecutive times (`times` must be sorted).
+pub fn time_intervals(times: &[u64]) -> Vec<u64> {
+    times.windows(2).map(|pair| pair[1] - pair[0]).collect()
+}
+
+/// Exponential fit `y = amplitude * exp(-rate * t)`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ExponentialFit {
+    /// expected counts in bin at Δt = 0
+    pub amplitude: f32,
+    /// rate in Hz
+    pub rate: f32,
+    /// rate standard error in Hz
+    pub rate_error: f32,
+    /// chi2 per degree of freedom (Pearson, with fitted values as variances)
+    pub chi2_ndf: f32,
+}
+
+impl ExponentialFit {
+    /// Fit histogram `y(x)` (x in µs) inside `range`.
+    ///
+    /// Uses weighted least squares on `ln(y)` (weight of the bin is its counts), empty bins are skipped.
+    /// Returns `None` if there are less than 3 non-empty bins.
+    pub fn fit(x: &[f32], y: &[f32], range: Range<f32>) -> Option<Self> {
+        let points = x
+            .iter()
+            .zip(y)
+            .filter(|(x, y)| range.contains(x) && **y > 0.0)
+            .map(|(x, y)| (*x as f64, *y as f64))
+            .collect::<Vec<_>>();
+        if points.len() < 3 {
+            return None;
+        }
+
+        let (mut s, mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (x, y) in &points {
+            let ln_y = y.ln();
+            s += y;
+            sx += y * x;
+            sy += y * ln_y;
+            sxx += y * x * x;
+            sxy += y * x * ln_y;
+        }
+        let det = s * sxx - sx * sx;
+        if det <= 0.0 {
+            return None;
+        }
+        let slope = (s * sxy - sx * sy) / det;
+        let intercept = (sxx * sy - sx * sxy) / det;
+
+        let chi2 = points
+            .iter()
+            .map(|(x, y)| {
+                let expected = (intercept + slope * x).exp();
+                (y - expected).powi(2) / expected
+            })
+            .sum::<f64>();
+
+        Some(Self {
+            amplitude: intercept.exp() as f32,
+            rate: (-slope * 1e6) as f32,
+            rate_error: ((s / det).sqrt() * 1e6) as f32,
+            chi2_ndf: (chi2 / (points.len() - 2) as f64) as f32,
+        })
+    }
+
+    /// Fitted counts for Δt in µs.
+    pub fn eval(&self, t: f32) -> f32 {
+        self.amplitude * (-self.rate * t * 1e-6).exp()
+    }
+}
+
+/// Histogram of time intervals with exponential fit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntervalDistribution {
+    /// intervals histogram in µs (single channel with index 0)
+    pub histogram: PointHistogram,
+    pub fit: Option<ExponentialFit>,
+}
+
+impl IntervalDistribution {
+    /// Build histogram of `intervals` (in ns) with `range` (in µs) and fit it inside `fit_range` (in µs).
+    ///
+    /// `fit_range` should start after the frame length/dead time region, where the distribution is distorted.
+    pub fn new(intervals: &[u64], range: Range<f32>, bins: usize, fit_range: Range<f32>) -> Self {
+        let mut histogram = PointHistogram::new(range, bins);
+        histogram.add_batch(
+            0,
+            intervals
+                .iter()
+                .map(|interval| *interval as f32 * 1e-3)
+                .collect(),
+        );
+        let fit = ExponentialFit::fit(&histogram.x, &histogram.merge_channels(), fit_range);
+        Self { histogram, fit }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(offset: EventOffset, channel: u8) -> (EventOffset, FrameEvent) {
+        (
+            offset,
+            FrameEvent::Event {
+                channel,
+                amplitude: 1.0,
+                size: 10,
+            },
+        )
+    }
+
+    #[test]
+    fn event_times_across_frames() {
+        let events = NumassEvents::from([
+            (
+                0,
+                vec![event(2_000, 0), (10, FrameEvent::Reset { size: 10 })],
+            ),
+            (
+                1_000,
+                vec![
+                    event(5, 0),
+                    event(3, 1),
+                    (
+                        0,
+                        FrameEvent::Overflow {
+                            channel: 0,
+                            size: 10,
+                        },
+                    ),
+                ],
+            ),
+        ]);
+        assert_eq!(event_times(&events, None), vec![1_003, 1_005, 2_000]);
+        assert_eq!(event_times(&events, Some(0)), vec![1_005, 2_000]);
+        assert!(event_times(&events, Some(2)).is_empty());
+        assert_eq!(trigger_times(&events), vec![0, 1_000]);
+
+        assert_eq!(time_intervals(&[1_003, 1_005, 2_000]), vec![2, 995]);
+        assert!(time_intervals(&[1_003]).is_empty());
+    }
+
+    #[test]
+    fn fit_recovers_rate() {
+        let (amplitude, rate) = (1_000.0f32, 20_000.0f32);
+        let x = (0..100).map(|idx| idx as f32 + 0.5).collect::<Vec<_>>();
+        let y = x
+            .iter()
+            .map(|x| amplitude * (-rate * x * 1e-6).exp())
+            .collect::<Vec<_>>();
+
+        let fit = ExponentialFit::fit(&x, &y, 10.0..100.0).unwrap();
+        assert!((fit.rate - rate).abs() < rate * 1e-3, "{fit:?}");
+        assert!(
+            (fit.amplitude - amplitude).abs() < amplitude * 1e-3,
+            "{fit:?}"
+        );
+        assert!(fit.chi2_ndf < 1e-3, "{fit:?}");
+        assert!((fit.eval(x[50]) - y[50]).abs() < y[50] * 1e-3);
+    }
+
+    #[test]
+    fn fit_not_enough_points() {
+        let x = [0.5, 1.5, 2.5, 3.5];
+        // only 2 bins inside range
+        assert_eq!(
+            ExponentialFit::fit(&x, &[4.0, 3.0, 2.0, 1.0], 1.0..3.0),
+            None
+        );
+        // empty bins are skipped
+        assert_eq!(ExponentialFit::fit(&x, &[0.0; 4], 0.0..4.0), None);
+        assert_eq!(
+            ExponentialFit::fit(&x, &[4.0, 0.0, 0.0, 1.0], 0.0..4.0),
+            None
+        );
+    }
+}
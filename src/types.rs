@@ -1,6 +1,6 @@
 //! Types used in the Numass processing.
 //! This module also contains some converters between types.
-use std::collections::BTreeMap;
+use std::{borrow::Cow, collections::BTreeMap, ops::Range};
 
 use numass::protos::rsb_event;
 use serde::{Deserialize, Serialize};
@@ -16,10 +16,18 @@ pub type NumassFrameFast<'a> = BTreeMap<u8, &'a [i16]>;
 /// Numass point conveted to frames (no copy, Point must be alive for this data to be valid).
 pub type NumassWaveformsFast<'a> = BTreeMap<u64, NumassFrameFast<'a>>;
 
+pub type NumassFrameCow<'a> = BTreeMap<u8, Cow<'a, [i16]>>;
+/// Numass point conveted to frames (waveforms are borrowed when possible, see [waveform_from_bytes](crate::preprocess::waveform_from_bytes)).
+pub type NumassWaveformsCow<'a> = BTreeMap<u64, NumassFrameCow<'a>>;
+
 /// Numass processed events type (both for processing + postprocessing and processing only).
 pub type NumassEvents = BTreeMap<u64, Vec<NumassEvent>>;
 /// Numass event (position in waveform in ns, amplitude).
-pub type NumassEvent = (u16, FrameEvent);
+pub type NumassEvent = (EventOffset, FrameEvent);
+/// Position of event in the frame in ns.
+///
+/// Was `u16` before, which overflowed after merging frames (see [compat] for old results).
+pub type EventOffset = u32;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FrameEvent {
@@ -40,6 +48,381 @@ pub enum FrameEvent {
     },
 }
 
+/// [EventTable::kinds] value for [FrameEvent::Event].
+pub const KIND_EVENT: u8 = 0;
+/// [EventTable::kinds] value for [FrameEvent::Overflow].
+pub const KIND_OVERFLOW: u8 = 1;
+/// [EventTable::kinds] value for [FrameEvent::Reset].
+pub const KIND_RESET: u8 = 2;
+/// [EventTable::kinds] value for [FrameEvent::Frame].
+pub const KIND_FRAME: u8 = 3;
+
+/// [EventTable::channels] value for events without channel ([FrameEvent::Reset] and [FrameEvent::Frame]).
+pub const NO_CHANNEL: u8 = u8::MAX;
+
+impl FrameEvent {
+    /// Flatten event into (`kind`, `channel`, `amplitude`, `size`) columns.
+    /// Missing values are filled with [NO_CHANNEL] and `NaN`.
+    pub fn to_columns(&self) -> (u8, u8, f32, u16) {
+        match self {
+            FrameEvent::Event {
+                channel,
+                amplitude,
+                size,
+            } => (KIND_EVENT, *channel, *amplitude, *size),
+            FrameEvent::Overflow { channel, size } => (KIND_OVERFLOW, *channel, f32::NAN, *size),
+            FrameEvent::Reset { size } => (KIND_RESET, NO_CHANNEL, f32::NAN, *size),
+            FrameEvent::Frame { size } => (KIND_FRAME, NO_CHANNEL, f32::NAN, *size),
+        }
+    }
+
+    /// Inverse of [to_columns](FrameEvent::to_columns). Fails on unknown `kind`.
+    pub fn from_columns(kind: u8, channel: u8, amplitude: f32, size: u16) -> Result<Self, String> {
+        match kind {
+            KIND_EVENT => Ok(FrameEvent::Event {
+                channel,
+                amplitude,
+                size,
+            }),
+            KIND_OVERFLOW => Ok(FrameEvent::Overflow { channel, size }),
+            KIND_RESET => Ok(FrameEvent::Reset { size }),
+            KIND_FRAME => Ok(FrameEvent::Frame { size }),
+            _ => Err(format!("unknown event kind {kind}")),
+        }
+    }
+}
+
+/// Processed events with provenance of merged events (see [MergedEvent]).
+pub type NumassMergedEvents = BTreeMap<u64, Vec<MergedEvent>>;
+
+/// Single event that contributed to [MergedEvent].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct EventPart {
+    /// position in the frame in ns
+    pub offset: EventOffset,
+    pub channel: u8,
+    pub amplitude: f32,
+}
+
+/// Event with provenance.
+///
+/// `event` is the same summed event as in [NumassEvents] (so it can be used for histograms),
+/// `parts` are the events merged into it (empty if event was not merged).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergedEvent {
+    pub offset: EventOffset,
+    pub event: FrameEvent,
+    pub parts: Vec<EventPart>,
+}
+
+impl MergedEvent {
+    /// Number of events merged into this one (1 for not merged events).
+    pub fn multiplicity(&self) -> usize {
+        self.parts.len().max(1)
+    }
+
+    /// Channels of contributing events.
+    pub fn channels(&self) -> Vec<u8> {
+        if self.parts.is_empty() {
+            match self.event {
+                FrameEvent::Event { channel, .. } | FrameEvent::Overflow { channel, .. } => {
+                    vec![channel]
+                }
+                _ => vec![],
+            }
+        } else {
+            self.parts.iter().map(|part| part.channel).collect()
+        }
+    }
+
+    fn as_part(&self) -> Option<EventPart> {
+        if let FrameEvent::Event {
+            channel, amplitude, ..
+        } = self.event
+        {
+            Some(EventPart {
+                offset: self.offset,
+                channel,
+                amplitude,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Merge `other` into this event: amplitudes are summed and contributors are recorded.
+    /// Does nothing if any of events is not [FrameEvent::Event].
+    pub fn absorb(&mut self, other: &MergedEvent) {
+        let (Some(part), Some(other_part)) = (self.as_part(), other.as_part()) else {
+            return;
+        };
+        if self.parts.is_empty() {
+            self.parts.push(part);
+        }
+        if other.parts.is_empty() {
+            self.parts.push(other_part);
+        } else {
+            self.parts.extend_from_slice(&other.parts);
+        }
+        if let FrameEvent::Event { amplitude, .. } = &mut self.event {
+            *amplitude += other_part.amplitude;
+        }
+    }
+}
+
+impl From<NumassEvent> for MergedEvent {
+    fn from((offset, event): NumassEvent) -> Self {
+        Self {
+            offset,
+            event,
+            parts: vec![],
+        }
+    }
+}
+
+impl From<MergedEvent> for NumassEvent {
+    fn from(event: MergedEvent) -> Self {
+        (event.offset, event.event)
+    }
+}
+
+/// Columnar (struct-of-arrays) alternative to [NumassEvents].
+///
+/// Frames are stored in time order, events of frame `idx` are stored in
+/// `frame_offsets[idx]..frame_offsets[idx + 1]` range of event columns.
+/// Event columns are the same as [FrameEvent::to_columns].
+///
+/// Table is checked with [validate](EventTable::validate) on deserialization,
+/// tables built with [push_frame](EventTable::push_frame) are always valid.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(try_from = "EventTableColumns")]
+pub struct EventTable {
+    pub frame_times: Vec<u64>,
+    /// start of each frame in event columns (`frame_times.len() + 1` elements)
+    pub frame_offsets: Vec<usize>,
+    /// position of event in the frame (in ns)
+    pub offsets: Vec<EventOffset>,
+    pub kinds: Vec<u8>,
+    pub channels: Vec<u8>,
+    pub amplitudes: Vec<f32>,
+    pub sizes: Vec<u16>,
+}
+
+/// Unchecked [EventTable] (deserialization helper).
+#[derive(Deserialize)]
+struct EventTableColumns {
+    frame_times: Vec<u64>,
+    frame_offsets: Vec<usize>,
+    offsets: Vec<EventOffset>,
+    kinds: Vec<u8>,
+    channels: Vec<u8>,
+    amplitudes: Vec<f32>,
+    sizes: Vec<u16>,
+}
+
+impl TryFrom<EventTableColumns> for EventTable {
+    type Error = String;
+
+    fn try_from(columns: EventTableColumns) -> Result<Self, Self::Error> {
+        let table = EventTable {
+            frame_times: columns.frame_times,
+            frame_offsets: columns.frame_offsets,
+            offsets: columns.offsets,
+            kinds: columns.kinds,
+            channels: columns.channels,
+            amplitudes: columns.amplitudes,
+            sizes: columns.sizes,
+        };
+        table.validate()?;
+        Ok(table)
+    }
+}
+
+impl Default for EventTable {
+    fn default() -> Self {
+        Self {
+            frame_times: vec![],
+            frame_offsets: vec![0],
+            offsets: vec![],
+            kinds: vec![],
+            channels: vec![],
+            amplitudes: vec![],
+            sizes: vec![],
+        }
+    }
+}
+
+impl EventTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of frames in the table.
+    pub fn frames_len(&self) -> usize {
+        self.frame_times.len()
+    }
+
+    /// Number of events in the table (all frames combined).
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Check columns consistency: equal column lengths, frame offsets cover event columns
+    /// in order, frame times are increasing and event kinds are known.
+    pub fn validate(&self) -> Result<(), String> {
+        let len = self.offsets.len();
+        for (name, column_len) in [
+            ("kinds", self.kinds.len()),
+            ("channels", self.channels.len()),
+            ("amplitudes", self.amplitudes.len()),
+            ("sizes", self.sizes.len()),
+        ] {
+            if column_len != len {
+                return Err(format!(
+                    "{name} length {column_len} != offsets length {len}"
+                ));
+            }
+        }
+
+        if self.frame_offsets.len() != self.frame_times.len() + 1 {
+            return Err(format!(
+                "frame_offsets length {} != frames number + 1 ({})",
+                self.frame_offsets.len(),
+                self.frame_times.len() + 1
+            ));
+        }
+        if self.frame_offsets.first() != Some(&0) || self.frame_offsets.last() != Some(&len) {
+            return Err("frame_offsets must start with 0 and end with events number".to_owned());
+        }
+        if self.frame_offsets.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err("frame_offsets are not sorted".to_owned());
+        }
+        if self.frame_times.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("frame_times are not increasing".to_owned());
+        }
+
+        if let Some(kind) = self.kinds.iter().find(|kind| **kind > KIND_FRAME) {
+            return Err(format!("unknown event kind {kind}"));
+        }
+
+        Ok(())
+    }
+
+    /// Append frame to the end of the table.
+    /// `time` must be greater than the time of the last frame.
+    pub fn push_frame(&mut self, time: u64, events: &[NumassEvent]) {
+        if let Some(last) = self.frame_times.last() {
+            assert!(time > *last, "frames must be pushed in time order");
+        }
+
+        self.frame_times.push(time);
+        for (offset, event) in events {
+            let (kind, channel, amplitude, size) = event.to_columns();
+            self.offsets.push(*offset);
+            self.kinds.push(kind);
+            self.channels.push(channel);
+            self.amplitudes.push(amplitude);
+            self.sizes.push(size);
+        }
+        self.frame_offsets.push(self.offsets.len());
+    }
+
+    /// Time and events range of frame `idx`.
+    pub fn frame(&self, idx: usize) -> (u64, Range<usize>) {
+        (
+            self.frame_times[idx],
+            self.frame_offsets[idx]..self.frame_offsets[idx + 1],
+        )
+    }
+
+    /// Iterate over frames as (time, events range).
+    pub fn frames(&self) -> impl Iterator<Item = (u64, Range<usize>)> + '_ {
+        (0..self.frames_len()).map(|idx| self.frame(idx))
+    }
+
+    /// Event `idx` (global index in event columns).
+    /// Panics if the table is not [valid](EventTable::validate).
+    pub fn event(&self, idx: usize) -> NumassEvent {
+        (
+            self.offsets[idx],
+            FrameEvent::from_columns(
+                self.kinds[idx],
+                self.channels[idx],
+                self.amplitudes[idx],
+                self.sizes[idx],
+            )
+            .expect("event table is not valid"),
+        )
+    }
+
+    /// Events of frame `idx` in [NumassEvents] representation.
+    pub fn frame_events(&self, idx: usize) -> Vec<NumassEvent> {
+        let (_, range) = self.frame(idx);
+        range.map(|idx| self.event(idx)).collect()
+    }
+
+    /// Consume table frame by frame in [NumassEvents] representation.
+    pub fn into_frames(self) -> impl Iterator<Item = (u64, Vec<NumassEvent>)> {
+        (0..self.frames_len()).map(move |idx| (self.frame_times[idx], self.frame_events(idx)))
+    }
+}
+
+impl FromIterator<(u64, Vec<NumassEvent>)> for EventTable {
+    fn from_iter<T: IntoIterator<Item = (u64, Vec<NumassEvent>)>>(iter: T) -> Self {
+        let mut table = EventTable::new();
+        for (time, events) in iter {
+            table.push_frame(time, &events);
+        }
+        table
+    }
+}
+
+impl From<&NumassEvents> for EventTable {
+    fn from(events: &NumassEvents) -> Self {
+        let events_len = events.values().map(Vec::len).sum();
+
+        let mut table = EventTable {
+            frame_times: Vec::with_capacity(events.len()),
+            frame_offsets: Vec::with_capacity(events.len() + 1),
+            offsets: Vec::with_capacity(events_len),
+            kinds: Vec::with_capacity(events_len),
+            channels: Vec::with_capacity(events_len),
+            amplitudes: Vec::with_capacity(events_len),
+            sizes: Vec::with_capacity(events_len),
+        };
+        table.frame_offsets.push(0);
+
+        for (time, events) in events {
+            table.push_frame(*time, events);
+        }
+        table
+    }
+}
+
+impl From<NumassEvents> for EventTable {
+    fn from(events: NumassEvents) -> Self {
+        events.into_iter().collect()
+    }
+}
+
+impl From<&EventTable> for NumassEvents {
+    fn from(table: &EventTable) -> Self {
+        (0..table.frames_len())
+            .map(|idx| (table.frame_times[idx], table.frame_events(idx)))
+            .collect()
+    }
+}
+
+impl From<EventTable> for NumassEvents {
+    fn from(table: EventTable) -> Self {
+        table.into_frames().collect()
+    }
+}
+
 impl std::hash::Hash for FrameEvent {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         core::mem::discriminant(self).hash(state);
@@ -115,3 +498,163 @@ impl From<ProcessedWaveform> for Vec<[f64; 2]> {
             .collect::<Vec<_>>()
     }
 }
+
+/// Compatibility with results saved when [EventOffset] was `u16`.
+///
+/// Old results (msgpack, json) are deserialized into current types as is, because integers are
+/// widened by serde. Conversions below are needed only to produce results for old readers.
+pub mod compat {
+    use std::collections::BTreeMap;
+
+    use super::{EventOffset, FrameEvent, NumassEvents};
+
+    /// [NumassEvent](super::NumassEvent) with `u16` offset.
+    pub type NumassEventV1 = (u16, FrameEvent);
+    /// [NumassEvents] with `u16` offsets.
+    pub type NumassEventsV1 = BTreeMap<u64, Vec<NumassEventV1>>;
+
+    pub fn events_from_v1(events: NumassEventsV1) -> NumassEvents {
+        events
+            .into_iter()
+            .map(|(time, events)| {
+                let events = events
+                    .into_iter()
+                    .map(|(offset, event)| (EventOffset::from(offset), event))
+                    .collect();
+                (time, events)
+            })
+            .collect()
+    }
+
+    /// Fails if any offset does not fit into `u16` (e.g. after merging frames).
+    pub fn events_to_v1(events: NumassEvents) -> Result<NumassEventsV1, String> {
+        events
+            .into_iter()
+            .map(|(time, events)| {
+                let events = events
+                    .into_iter()
+                    .map(|(offset, event)| {
+                        u16::try_from(offset)
+                            .map(|offset| (offset, event))
+                            .map_err(|_| {
+                                format!("event offset {offset} in frame {time} overflows u16")
+                            })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((time, events))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> EventTable {
+        [
+            (
+                10,
+                vec![
+                    (
+                        0,
+                        FrameEvent::Event {
+                            channel: 1,
+                            amplitude: 2.0,
+                            size: 3,
+                        },
+                    ),
+                    (8, FrameEvent::Reset { size: 4 }),
+                ],
+            ),
+            (
+                20,
+                vec![(
+                    16,
+                    FrameEvent::Overflow {
+                        channel: 2,
+                        size: 5,
+                    },
+                )],
+            ),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn unknown_kind() {
+        assert!(FrameEvent::from_columns(KIND_FRAME + 1, 0, 0.0, 0).is_err());
+    }
+
+    #[test]
+    fn table_deserialize_validates() {
+        let table = table();
+        // NaN amplitudes can't be compared, so tables are compared through columns
+        let restored: EventTable =
+            rmp_serde::from_slice(&rmp_serde::to_vec(&table).unwrap()).unwrap();
+        assert_eq!(restored.frame_offsets, table.frame_offsets);
+        assert_eq!(restored.kinds, table.kinds);
+
+        let corrupt = |corrupt: fn(&mut EventTable)| {
+            let mut table = table.clone();
+            corrupt(&mut table);
+            rmp_serde::from_slice::<EventTable>(&rmp_serde::to_vec(&table).unwrap())
+        };
+        assert!(corrupt(|table| table.kinds[0] = 42).is_err());
+        assert!(corrupt(|table| {
+            table.sizes.pop();
+        })
+        .is_err());
+        assert!(corrupt(|table| table.frame_offsets[1] = 5).is_err());
+        assert!(corrupt(|table| table.frame_offsets.insert(1, 1)).is_err());
+        assert!(corrupt(|table| table.frame_times[1] = 10).is_err());
+        assert!(corrupt(|table| {
+            table.frame_offsets.pop();
+        })
+        .is_err());
+    }
+
+    /// Old events with `u16` offsets (only [FrameEvent::Event]s, so tables can be compared).
+    fn events_v1() -> compat::NumassEventsV1 {
+        let event = |offset, channel| {
+            (
+                offset,
+                FrameEvent::Event {
+                    channel,
+                    amplitude: offset as f32,
+                    size: 3,
+                },
+            )
+        };
+        [
+            (10, vec![event(0, 1), event(8, 2)]),
+            (20, vec![event(u16::MAX, 6)]),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn compat_round_trip() {
+        let events = compat::events_from_v1(events_v1());
+        assert_eq!(events[&20][0].0, u16::MAX as EventOffset);
+
+        let restored = compat::events_from_v1(compat::events_to_v1(events.clone()).unwrap());
+        assert_eq!(EventTable::from(restored), EventTable::from(events.clone()));
+
+        let mut overflow = events;
+        overflow.get_mut(&20).unwrap()[0].0 += 1;
+        assert!(compat::events_to_v1(overflow).is_err());
+    }
+
+    #[test]
+    fn deserialize_u16_offsets() {
+        let blob = rmp_serde::to_vec(&events_v1()).unwrap();
+        let events: NumassEvents = rmp_serde::from_slice(&blob).unwrap();
+        assert_eq!(
+            EventTable::from(events),
+            EventTable::from(compat::events_from_v1(events_v1()))
+        );
+    }
+}
@@ -1,7 +1,9 @@
 //! # Utils
 //! This module contains some utility functions not related to processing or postprocessing.
 
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "plotly")]
 use plotly::color::Color;
@@ -14,14 +16,58 @@ use {
 
 use crate::{
     histogram::{HistogramParams, PointHistogram},
-    types::{FrameEvent, NumassEvents},
+    types::{EventTable, FrameEvent, NumassEvent, NumassEvents, KIND_EVENT},
 };
 
 pub fn events_to_histogram(amplitudes: NumassEvents, histogram: HistogramParams) -> PointHistogram {
+    fill_histogram(amplitudes.into_iter(), histogram, None)
+}
+
+/// Counters of processed events accumulated by [fill_histogram].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventCounters {
+    pub frames: usize,
+    /// events count per channel (including events outside of histogram range)
+    pub events: BTreeMap<u8, usize>,
+    pub overflows: usize,
+    pub resets: usize,
+}
+
+impl EventCounters {
+    pub fn add_frame(&mut self, events: &[NumassEvent]) {
+        self.frames += 1;
+        for (_, event) in events {
+            match event {
+                FrameEvent::Event { channel, .. } => *self.events.entry(*channel).or_default() += 1,
+                FrameEvent::Overflow { .. } => self.overflows += 1,
+                FrameEvent::Reset { .. } => self.resets += 1,
+                FrameEvent::Frame { .. } => {}
+            }
+        }
+    }
+
+    /// Events rate (all channels combined) in Hz for the given time in nanoseconds
+    /// (e.g. [Preprocess::effective_time](crate::preprocess::Preprocess::effective_time)).
+    pub fn rate(&self, time: u64) -> f64 {
+        self.events.values().sum::<usize>() as f64 / (time as f64 * 1e-9)
+    }
+}
+
+/// Fill histogram frame by frame without collecting events.
+/// Can be combined with [extract_events_iter](crate::process::extract_events_iter)
+/// and [post_process_frames](crate::postprocess::post_process_frames) to keep memory flat.
+pub fn fill_histogram(
+    frames: impl Iterator<Item = (u64, Vec<NumassEvent>)>,
+    histogram: HistogramParams,
+    mut counters: Option<&mut EventCounters>,
+) -> PointHistogram {
     let mut histogram = PointHistogram::from(histogram);
 
-    for (_, channels) in amplitudes {
-        for (_, event) in channels {
+    for (_, events) in frames {
+        if let Some(counters) = counters.as_mut() {
+            counters.add_frame(&events);
+        }
+        for (_, event) in events {
             if let FrameEvent::Event {
                 channel, amplitude, ..
             } = event
@@ -34,6 +80,24 @@ pub fn events_to_histogram(amplitudes: NumassEvents, histogram: HistogramParams)
     histogram
 }
 
+/// Same as [events_to_histogram] but for [EventTable] (iterates over columns directly).
+pub fn table_to_histogram(table: &EventTable, histogram: HistogramParams) -> PointHistogram {
+    let mut histogram = PointHistogram::from(histogram);
+
+    table
+        .kinds
+        .iter()
+        .zip(&table.channels)
+        .zip(&table.amplitudes)
+        .for_each(|((kind, channel), amplitude)| {
+            if *kind == KIND_EVENT {
+                histogram.add(*channel, *amplitude)
+            }
+        });
+
+    histogram
+}
+
 /// Convert path in db (RUN/FILL/SET/POINT) to filename with info preserved.
 pub fn construct_filename(name: &str, pref_ext: Option<&str>) -> String {
     let temp = PathBuf::from(name);
@@ -5,7 +5,7 @@
 use crate::{
     histogram::{HistogramParams, PointHistogram},
     postprocess::PostProcessParams,
-    preprocess::Preprocess,
+    preprocess::{BadBlockParams, Preprocess},
     process::{ProcessParams, TRAPEZOID_DEFAULT},
 };
 use serde::{Deserialize, Serialize};
@@ -25,6 +25,7 @@ impl Default for ViewerState {
             process: ProcessParams {
                 algorithm: TRAPEZOID_DEFAULT,
                 convert_to_kev: true,
+                bad_blocks: BadBlockParams::default(),
             },
             post_process: PostProcessParams::default(),
             histogram: HistogramParams {
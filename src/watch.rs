@@ -0,0 +1,244 @@
+//! # Watch
+//! Live updates of the storage tree during data taking.
+//!
+//! - [FSWatcher] - native watcher based on filesystem notifications
+//! - [FSPoller] - polling watcher (works on both native and wasm, uses `api/ls` on wasm)
+//!
+//! Both produce [FSEvent]s that can be applied to the tree with [FSRepr::apply_event].
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::storage::{FSEvent, FSRepr, LoadState};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::FSWatcher;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use std::path::Path;
+
+    use notify::{
+        event::{ModifyKind, RenameMode},
+        EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    };
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    use crate::storage::{FSEvent, FSRepr, LoadState};
+
+    /// Native storage watcher (inotify/FSEvents/ReadDirectoryChangesW depending on the platform).
+    pub struct FSWatcher {
+        _watcher: RecommendedWatcher,
+        receiver: UnboundedReceiver<FSEvent>,
+    }
+
+    impl FSWatcher {
+        /// Start watching `root` recursively.
+        pub fn new(root: &Path) -> notify::Result<Self> {
+            let (sender, receiver) = unbounded_channel();
+
+            let mut watcher =
+                notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                    if let Ok(event) = event {
+                        for event in convert_event(event) {
+                            let _ = sender.send(event);
+                        }
+                    }
+                })?;
+            watcher.watch(root, RecursiveMode::Recursive)?;
+
+            Ok(Self {
+                _watcher: watcher,
+                receiver,
+            })
+        }
+
+        /// Wait for the next change.
+        pub async fn next(&mut self) -> Option<FSEvent> {
+            self.receiver.recv().await
+        }
+
+        /// Get next change if available (non-blocking, e.g. for calling from UI loop).
+        pub fn try_next(&mut self) -> Option<FSEvent> {
+            self.receiver.try_recv().ok()
+        }
+    }
+
+    fn added(path: &Path) -> Option<FSEvent> {
+        let meta = std::fs::metadata(path).ok()?;
+        let modified = meta.modified().ok()?;
+        let path = path.to_owned();
+        Some(FSEvent::Added(if meta.is_dir() {
+            FSRepr::Directory {
+                path,
+                children: vec![],
+                modified,
+                load_state: LoadState::NotLoaded,
+            }
+        } else {
+            FSRepr::File { path, modified }
+        }))
+    }
+
+    fn modified(path: &Path) -> Option<FSEvent> {
+        let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+        Some(FSEvent::Modified {
+            path: path.to_owned(),
+            modified,
+        })
+    }
+
+    fn convert_event(event: notify::Event) -> Vec<FSEvent> {
+        let paths = event.paths;
+        match event.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                paths.iter().filter_map(|path| added(path)).collect()
+            }
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                paths.into_iter().map(FSEvent::Removed).collect()
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if paths.len() == 2 => {
+                let mut events = vec![FSEvent::Removed(paths[0].to_owned())];
+                events.extend(added(&paths[1]));
+                events
+            }
+            EventKind::Modify(_) => paths.iter().filter_map(|path| modified(path)).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Polling storage watcher.
+/// Reloads the tree every `interval` and reports the difference.
+pub struct FSPoller {
+    tree: FSRepr,
+    interval: Duration,
+}
+
+impl FSPoller {
+    /// `tree` is a current state of the tree (only loaded directories are polled).
+    pub fn new(tree: FSRepr, interval: Duration) -> Self {
+        Self { tree, interval }
+    }
+
+    /// Wait `interval` and return changes since the last poll.
+    pub async fn next(&mut self) -> Vec<FSEvent> {
+        #[cfg(not(target_arch = "wasm32"))]
+        tokio::time::sleep(self.interval).await;
+        #[cfg(target_arch = "wasm32")]
+        gloo::timers::future::sleep(self.interval).await;
+
+        self.poll().await
+    }
+
+    /// Reload the tree immediately and return changes since the last poll.
+    pub async fn poll(&mut self) -> Vec<FSEvent> {
+        let before = flatten(&self.tree);
+        self.tree.reload().await;
+        let after = flatten(&self.tree);
+
+        let mut events = before
+            .keys()
+            .filter(|path| !after.contains_key(*path))
+            .map(|path| FSEvent::Removed(path.to_owned()))
+            .collect::<Vec<_>>();
+
+        for (path, node) in after {
+            match before.get(&path) {
+                None => events.push(FSEvent::Added(node)),
+                Some(old) if modified_time(old) != modified_time(&node) => {
+                    events.push(FSEvent::Modified {
+                        path,
+                        modified: modified_time(&node),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+
+        events
+    }
+}
+
+fn modified_time(node: &FSRepr) -> SystemTime {
+    match node {
+        FSRepr::File { modified, .. } | FSRepr::Directory { modified, .. } => *modified,
+    }
+}
+
+/// Collect loaded part of the tree as (path -> node without children).
+fn flatten(tree: &FSRepr) -> BTreeMap<PathBuf, FSRepr> {
+    let mut nodes = BTreeMap::new();
+    let mut current = vec![tree];
+    while let Some(node) = current.pop() {
+        let flat = match node {
+            FSRepr::File { .. } => node.to_owned(),
+            FSRepr::Directory {
+                path,
+                children,
+                modified,
+                load_state,
+            } => {
+                if *load_state == LoadState::Loaded {
+                    current.extend(children.iter());
+                }
+                FSRepr::Directory {
+                    path: path.to_owned(),
+                    children: vec![],
+                    modified: *modified,
+                    load_state: LoadState::NotLoaded,
+                }
+            }
+        };
+        nodes.insert(node.to_filename(), flat);
+    }
+    nodes
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn poll_removed_paths() {
+        let root = std::env::temp_dir().join(format!("processing-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("set_1")).unwrap();
+        std::fs::write(root.join("set_1/p1"), b"").unwrap();
+        std::fs::write(root.join("p0"), b"").unwrap();
+
+        let mut tree = FSRepr::new(root.clone());
+        if let FSRepr::Directory { load_state, .. } = &mut tree {
+            *load_state = LoadState::Loaded;
+        }
+        tree.reload().await;
+        if let Some(FSRepr::Directory { load_state, .. }) = tree.find_mut(&root.join("set_1")) {
+            *load_state = LoadState::Loaded;
+        }
+        let mut poller = FSPoller::new(tree, Duration::ZERO);
+        poller.poll().await;
+
+        std::fs::remove_file(root.join("p0")).unwrap();
+        std::fs::remove_dir_all(root.join("set_1")).unwrap();
+        let mut removed = poller
+            .poll()
+            .await
+            .into_iter()
+            .filter_map(|event| match event {
+                FSEvent::Removed(path) => Some(path),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![root.join("p0"), root.join("set_1"), root.join("set_1/p1")]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(FSRepr::ls(root.clone()).await.is_none());
+        assert!(poller.poll().await.is_empty());
+    }
+}
@@ -1,7 +1,7 @@
 //! This module contains egui widgets for processing configurations
 
 use crate::{
-    histogram::HistogramParams, postprocess::PostProcessParams, preprocess::{CHECK_BIN_SIZE, CHECK_HV_THRESHOLD, CUTOFF_BIN_SIZE}, process::{
+    histogram::HistogramParams, postprocess::{MergeWindow, PostProcessParams}, preprocess::BadBlockParams, process::{
         Algorithm, HWResetParams, ProcessParams, FIRSTPEAK_DEFAULT, LIKHOVID_DEFAULT,
         LONGDIFF_DEFAULT, TRAPEZOID_DEFAULT,
     }
@@ -30,7 +30,7 @@ impl UserInput for HistogramParams {
 }
 
 impl UserInput for ProcessParams {
-    fn input(&self, ui: &mut egui::Ui, _: &egui::Context) -> Self {
+    fn input(&self, ui: &mut egui::Ui, ctx: &egui::Context) -> Self {
         let mut algorithm = self.algorithm.to_owned();
 
         ui.label("Processing params");
@@ -201,9 +201,114 @@ impl UserInput for ProcessParams {
         let mut convert_to_kev = self.convert_to_kev;
         ui.checkbox(&mut convert_to_kev, "convert to keV");
 
+        let bad_blocks = ui
+            .collapsing("bad blocks", |ui| self.bad_blocks.input(ui, ctx))
+            .body_returned
+            .unwrap_or(self.bad_blocks);
+
         ProcessParams {
             algorithm,
             convert_to_kev,
+            bad_blocks,
+        }
+    }
+}
+
+impl UserInput for BadBlockParams {
+    fn input(&self, ui: &mut egui::Ui, _: &egui::Context) -> Self {
+        let mut cutoff_bin_size = self.cutoff_bin_size as f32 * 1e-9;
+        ui.add(egui::Slider::new(&mut cutoff_bin_size, 0.1..=10.0).text("block size (s)"));
+
+        let mut check_bin_size = self.check_bin_size as f32 * 1e-6;
+        ui.add(egui::Slider::new(&mut check_bin_size, 1.0..=1000.0).text("sub-block size (ms)"))
+            .on_hover_text("sub-block can't be larger than block");
+
+        let mut hv_threshold = self.hv_threshold as f32 * 1e-3;
+        ui.add(egui::Slider::new(&mut hv_threshold, 0.0..=30.0).text("HV threshold (kV)"))
+            .on_hover_text("points with HV above threshold are not checked");
+
+        ui.label("criteria");
+
+        let mut empty_bins = self.empty_bins;
+        ui.checkbox(&mut empty_bins, "empty sub-blocks")
+            .on_hover_text("block is bad if any of its sub-blocks contains 0 triggers");
+
+        let mut rate_spike_sigma = self.rate_spike_sigma;
+        ui.horizontal(|ui| {
+            let mut checked = rate_spike_sigma.is_some();
+            ui.checkbox(&mut checked, "rate spikes")
+                .on_hover_text("block is bad if any of its sub-blocks trigger count exceeds expected (median) by N sigma");
+            rate_spike_sigma = if checked {
+                let mut sigma = rate_spike_sigma.unwrap_or(5);
+                ui.add(egui::Slider::new(&mut sigma, 1..=20).text("sigma"));
+                Some(sigma)
+            } else {
+                None
+            };
+        });
+
+        let mut max_resets = self.max_resets;
+        ui.horizontal(|ui| {
+            let mut checked = max_resets.is_some();
+            ui.checkbox(&mut checked, "reset storms")
+                .on_hover_text("block is bad if it contains more HW resets than limit");
+            max_resets = if checked {
+                let mut limit = max_resets.unwrap_or(100);
+                ui.add(egui::Slider::new(&mut limit, 0..=10000).text("max resets"));
+                Some(limit)
+            } else {
+                None
+            };
+        });
+
+        let mut max_overflows = self.max_overflows;
+        ui.horizontal(|ui| {
+            let mut checked = max_overflows.is_some();
+            ui.checkbox(&mut checked, "overflow bursts")
+                .on_hover_text("block is bad if it contains more overflowed waveforms than limit");
+            max_overflows = if checked {
+                let mut limit = max_overflows.unwrap_or(100);
+                ui.add(egui::Slider::new(&mut limit, 0..=10000).text("max overflows"));
+                Some(limit)
+            } else {
+                None
+            };
+        });
+
+        // same limits as in BadBlockParams::validate
+        let cutoff_bin_size = ((cutoff_bin_size * 1e9) as u64).max(1);
+        let check_bin_size = ((check_bin_size * 1e6) as u64).clamp(1, cutoff_bin_size);
+
+        BadBlockParams {
+            cutoff_bin_size,
+            check_bin_size,
+            hv_threshold: (hv_threshold * 1e3).round() as u32,
+            empty_bins,
+            rate_spike_sigma,
+            max_resets,
+            max_overflows,
+        }
+    }
+}
+
+impl UserInput for MergeWindow {
+    fn input(&self, ui: &mut egui::Ui, _: &egui::Context) -> Self {
+        let (mut base, mut slope, mut max) = match *self {
+            MergeWindow::Fixed(window) => (window, 0.0, u16::MAX),
+            MergeWindow::Linear { base, slope, max } => (base, slope, max),
+        };
+
+        let mut linear = matches!(self, MergeWindow::Linear { .. });
+        ui.checkbox(&mut linear, "energy dependent")
+            .on_hover_text("window = base + slope * amplitude (limited by max)");
+
+        ui.add(egui::Slider::new(&mut base, 0..=10000).text("window (ns)"));
+        if linear {
+            ui.add(egui::Slider::new(&mut slope, -100.0..=100.0).text("slope (ns per amplitude unit)"));
+            ui.add(egui::Slider::new(&mut max, 0..=u16::MAX).text("max (ns)"));
+            MergeWindow::Linear { base, slope, max }
+        } else {
+            MergeWindow::Fixed(base)
         }
     }
 }
@@ -215,22 +320,18 @@ impl UserInput for PostProcessParams {
         let mut merge_splits_first = self.merge_splits_first;
         let mut merge_close_events = self.merge_close_events;
         let mut ignore_borders = self.ignore_borders;
+        let mut split_window = self.split_window;
+        let mut merge_window = self.merge_window;
         let mut ignore_channels: [bool; 7] = self.ignore_channels.to_owned().into();
 
         ui.add_enabled_ui(true, |ui| {
             ui.label("Postprocessing params");
 
-            ui.checkbox(&mut cut_bad_blocks, "cut_bad_blocks").on_hover_text(
-                format!("
-                point is splitted into {} s length blocks 
-                each block is also be splitted into {} s subblock
-                the block is considered bad if any of its subblocks contains 0 events
-                the cutting applies only on points with HV < {} keV
-                ", 
-                    CUTOFF_BIN_SIZE  as f32 * 1e-9,
-                    CHECK_BIN_SIZE as f32 * 1e-9,
-                    CHECK_HV_THRESHOLD * 1e-3
-                )
+            ui.checkbox(&mut cut_bad_blocks, "cut_bad_blocks").on_hover_text("
+                remove events inside bad blocks
+                bad blocks are detected in preprocessing
+                (see \"bad blocks\" section of processing params)
+                "
             );
 
             ui.horizontal(|ui| {
@@ -263,12 +364,18 @@ impl UserInput for PostProcessParams {
             ui.checkbox(&mut merge_splits_first, "merge splits first")
                 .on_hover_text(
                     "
-                    split - neighboring events with time delta < 200 ns
+                    split - events close in time to the central pixel event (see split window)
                     splits will be merged into one event in random order
                     (based on which one is first)
                     this will be processed before main merging
                     "
                 );
+            if merge_splits_first {
+                split_window = ui
+                    .collapsing("split window", |ui| split_window.input(ui, ctx))
+                    .body_returned
+                    .unwrap_or(split_window);
+            }
             ui.checkbox(&mut merge_close_events, "merge close events")
                 .on_hover_text(
                     "
@@ -279,6 +386,23 @@ impl UserInput for PostProcessParams {
                     if idx_1 and idx_2 are neigbors => idx_1.amp += idx_2.amp and idx_2 will be removed
                     "
                 );
+            if merge_close_events {
+                let mut windowed = merge_window.is_some();
+                ui.checkbox(&mut windowed, "merge window").on_hover_text("
+                merge only events closer in time than window to idx_1
+                otherwise events are merged regardless of time distance
+                ");
+                merge_window = if windowed {
+                    let window = merge_window.unwrap_or_default();
+                    Some(
+                        ui.collapsing("merge window", |ui| window.input(ui, ctx))
+                            .body_returned
+                            .unwrap_or(window),
+                    )
+                } else {
+                    None
+                };
+            }
             ui.checkbox(&mut ignore_borders, "ignore borders").on_hover_text("
             do not check for neigboorhooding at merging step
             with this flag every event in frame will be merged into first one
@@ -316,6 +440,8 @@ impl UserInput for PostProcessParams {
             merge_splits_first,
             merge_close_events,
             ignore_borders,
+            split_window,
+            merge_window,
             ignore_channels: ignore_channels.into(),
         }
     }